@@ -1,47 +1,284 @@
+extern crate bytes;
 extern crate futures;
 extern crate hyper;
 extern crate hyper_tls;
+extern crate rand;
 extern crate serde_json;
+extern crate tokio;
 
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
 use futures::future;
 use futures::prelude::*;
+use futures::stream;
 use hyper::client::HttpConnector;
-use hyper::{header, Body, Client as HTTPClient, Method, Request, Response};
+use hyper::{header, Body, Client as HTTPClient, Method, Request, Response, StatusCode};
 use hyper_tls::HttpsConnector;
+use rand::{thread_rng, Rng};
+use tokio::timer::{Delay, Timeout};
 
 use crate::config::Config;
-use crate::data::Data;
+use crate::connector::TimeoutConnector;
+use crate::data::{Archive, Data};
 use crate::error::Error;
+use crate::health::Health;
+use crate::limiter::Limiter;
 use crate::message::{common, response};
 
 type FuturePing = Box<Future<Item = Option<common::Ping>, Error = Error> + Send>;
 type FutureFetch = Box<Future<Item = response::Fetch, Error = Error> + Send>;
 type FutureEmpty = Box<Future<Item = (), Error = Error> + Send>;
+type FutureDrain = Box<Future<Item = response::Drain, Error = Error> + Send>;
+type HTTPSClient = HTTPClient<TimeoutConnector<HttpsConnector<HttpConnector>>>;
 
 const CONNECTOR_THREADS: usize = 4;
 
+// Request headers passed through to the peer on fetch
+const FORWARD_HEADERS: &[header::HeaderName] = &[
+    header::ACCEPT_ENCODING,
+    header::RANGE,
+    header::IF_RANGE,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+];
+
+// Response headers that are not passed back from the peer on fetch
+const SKIP_HEADERS: &[header::HeaderName] = &[
+    header::CONNECTION,
+    header::TRANSFER_ENCODING,
+    header::CONTENT_LENGTH,
+    header::CONTENT_TYPE,
+    header::DATE,
+];
+
+// Marks 404 responses of peers that serve container's own not found page
+pub const NOT_FOUND_PAGE: &str = "x-naught-not-found";
+
+// Size of body chunks on store, progress of upload is tracked per chunk
+const STORE_CHUNK_SIZE: usize = 64 * 1024;
+
+// Upper bound for delay between store attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
 pub struct Client {
-    client: HTTPClient<HttpsConnector<HttpConnector>>,
+    client: HTTPSClient,
     sender: String,
     auth: String,
+    replications: Limiter,
+    request_timeout: Duration,
+    idle_timeout: Duration,
+    store_attempts: u32,
+    retry_backoff: Duration,
+    health: Health,
+}
+
+// Upper bound of delay before retrying `attempt`, `backoff` doubled on every
+// attempt
+fn max_retry_delay(backoff: Duration, attempt: u32) -> Duration {
+    2u32.checked_pow(attempt)
+        .and_then(|factor| backoff.checked_mul(factor))
+        .map_or(MAX_RETRY_DELAY, |delay| {
+            std::cmp::min(delay, MAX_RETRY_DELAY)
+        })
+}
+
+// Call `f` until it succeeds or runs out of attempts. Delay before each
+// retry is random, up to `max_retry_delay`.
+fn retry<F>(f: Arc<F>, attempt: u32, attempts: u32, backoff: Duration) -> FutureEmpty
+where
+    F: Fn() -> FutureEmpty + Send + Sync + 'static,
+{
+    Box::new(f().or_else(move |err| -> FutureEmpty {
+        if attempt + 1 >= attempts {
+            return Box::new(future::err(err));
+        }
+
+        let delay = max_retry_delay(backoff, attempt).mul_f64(thread_rng().gen::<f64>());
+        trace!(
+            "retrying attempt: {} in: {:?} after error: {:?}",
+            attempt + 1,
+            delay,
+            err
+        );
+
+        Box::new(
+            Delay::new(Instant::now() + delay)
+                .map_err(|_| Error::TimerError)
+                .and_then(move |_| retry(f, attempt + 1, attempts, backoff)),
+        )
+    }))
+}
+
+// Fail with `Error::Timeout` unless `f` completes in time. The deadline is
+// set on first poll, so that futures queued behind others get the full
+// `timeout`.
+fn with_timeout<F>(f: F, timeout: Duration) -> impl Future<Item = F::Item, Error = Error>
+where
+    F: Future<Error = Error>,
+{
+    future::lazy(move || Timeout::new(f, timeout)).map_err(|err| {
+        if err.is_elapsed() {
+            Error::Timeout
+        } else {
+            err.into_inner().unwrap_or(Error::TimerError)
+        }
+    })
+}
+
+// Fail the body with `Error::Timeout` if no data arrives in time
+fn with_idle_timeout(body: Body, timeout: Duration) -> Body {
+    Body::wrap_stream(Timeout::new(body, timeout).map_err(|err| {
+        if err.is_elapsed() {
+            Error::Timeout
+        } else {
+            err.into_inner()
+                .map(Error::from)
+                .unwrap_or(Error::TimerError)
+        }
+    }))
+}
+
+// Fail with `Error::Timeout` once `progress` has not been touched for
+// `timeout`. `progress` is touched on first poll too.
+struct IdleTimeout<F> {
+    inner: F,
+    progress: Arc<Mutex<Instant>>,
+    timeout: Duration,
+    delay: Option<Delay>,
+}
+
+impl<F> IdleTimeout<F> {
+    fn new(inner: F, progress: Arc<Mutex<Instant>>, timeout: Duration) -> Self {
+        IdleTimeout {
+            inner,
+            progress,
+            timeout,
+            delay: None,
+        }
+    }
+}
+
+impl<F> Future for IdleTimeout<F>
+where
+    F: Future<Error = Error>,
+{
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<F::Item, Error> {
+        if let Async::Ready(item) = self.inner.poll()? {
+            return Ok(Async::Ready(item));
+        }
+
+        let mut progress = self.progress.lock().expect("lock to acquire");
+        if self.delay.is_none() {
+            *progress = Instant::now();
+        }
+        let deadline = *progress + self.timeout;
+        drop(progress);
+
+        let delay = self.delay.get_or_insert_with(|| Delay::new(deadline));
+        if delay.deadline() < deadline {
+            delay.reset(deadline);
+        }
+
+        match delay.poll().map_err(|_| Error::TimerError)? {
+            Async::Ready(()) => Err(Error::Timeout),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+// Send `blob` in chunks, touching `progress` whenever the next one is taken
+fn progress_body(blob: &Bytes, progress: Arc<Mutex<Instant>>) -> Body {
+    let chunks = (0..blob.len())
+        .step_by(STORE_CHUNK_SIZE)
+        .map(|start| blob.slice(start, std::cmp::min(start + STORE_CHUNK_SIZE, blob.len())))
+        .collect::<Vec<_>>();
+
+    Body::wrap_stream(
+        stream::iter_ok::<_, std::io::Error>(chunks).inspect(move |_| {
+            *progress.lock().expect("lock to acquire") = Instant::now();
+        }),
+    )
+}
+
+fn send(
+    client: &HTTPSClient,
+    request: Request<Body>,
+    timeout: Duration,
+) -> impl Future<Item = Response<Body>, Error = Error> {
+    with_timeout(client.request(request).from_err::<Error>(), timeout)
+}
+
+// Fails fast when the circuit of the peer is open and records the outcome of
+// `f`. Responses other than 5xx count as successes.
+fn send_tracked<F>(
+    health: &Health,
+    peer_uri: &str,
+    f: F,
+) -> Box<Future<Item = Response<Body>, Error = Error> + Send>
+where
+    F: Future<Item = Response<Body>, Error = Error> + Send + 'static,
+{
+    if !health.allows(peer_uri) {
+        return Box::new(future::err(Error::CircuitOpen(peer_uri.to_string())));
+    }
+
+    let health = health.clone();
+    let peer_uri = peer_uri.to_string();
+    let start = Instant::now();
+    Box::new(f.then(move |result| {
+        let success = match result {
+            Ok(ref response) => !response.status().is_server_error(),
+            Err(_) => false,
+        };
+        health.record(&peer_uri, success, start.elapsed());
+        result
+    }))
 }
 
 impl Client {
     pub fn new(config: &Config, sender: &str) -> Self {
         let connector = HttpsConnector::new(CONNECTOR_THREADS).expect("Connector to instantiate");
 
+        let connector = TimeoutConnector::new(connector, config.connect_timeout);
+
         let client = HTTPClient::builder().build::<_, Body>(connector);
 
         Client {
             client,
             sender: sender.to_string(),
             auth: config.get_auth(),
+            replications: Limiter::new(config.max_replications),
+            request_timeout: config.request_timeout,
+            idle_timeout: config.idle_timeout,
+            store_attempts: config.store_attempts,
+            retry_backoff: config.retry_backoff,
+            health: Health::default(),
         }
     }
 
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
     pub fn ping(&self, peer_uri: &str, json_ping: &str) -> FuturePing {
         let uri = format!("{}/_ping", peer_uri);
+        self.post_ping(uri, json_ping, self.request_timeout)
+    }
 
+    // Ping `target` through `peer_uri`. The peer pings the target itself, so
+    // it needs more time than a direct ping.
+    pub fn ping_req(&self, peer_uri: &str, json_ping_req: &str) -> FuturePing {
+        let uri = format!("{}/_ping-req", peer_uri);
+        self.post_ping(uri, json_ping_req, self.request_timeout * 2)
+    }
+
+    fn post_ping(&self, uri: String, json: &str, timeout: Duration) -> FuturePing {
         let request = Request::builder()
             .method(Method::POST)
             .uri(uri)
@@ -49,7 +286,7 @@ impl Client {
             .header(header::CONTENT_TYPE, "application/json")
             .header(header::ACCEPT, "application/json")
             .header("x-naught-sender", self.sender.clone())
-            .body(Body::from(json_ping.to_string()));
+            .body(Body::from(json.to_string()));
 
         let request = match request {
             Ok(request) => request,
@@ -58,11 +295,7 @@ impl Client {
             }
         };
 
-        // TODO(indutny): timeout
-        let f = self
-            .client
-            .request(request)
-            .from_err::<Error>()
+        let f = send(&self.client, request, timeout)
             .and_then(|response| {
                 let is_success = if response.status().is_success() {
                     future::ok(())
@@ -75,10 +308,50 @@ impl Client {
             .and_then(|ping| future::ok(Some(ping)))
             .from_err::<Error>();
 
-        Box::new(f)
+        // Response body is small, so it should arrive in time too
+        Box::new(with_timeout(f, timeout))
+    }
+
+    pub fn drain(&self, peer_uri: &str) -> FutureDrain {
+        let uri = format!("{}/_drain", peer_uri);
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::AUTHORIZATION, self.auth.clone())
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty());
+
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                return Box::new(future::err(Error::from(err)));
+            }
+        };
+
+        let f = send(&self.client, request, self.request_timeout)
+            .and_then(|response| {
+                let is_success = if response.status().is_success() {
+                    future::ok(())
+                } else {
+                    future::err(Error::DrainFailed)
+                };
+                is_success.and_then(|_| response.into_body().concat2().from_err())
+            })
+            .and_then(|chunk| {
+                serde_json::from_slice::<response::Drain>(&chunk).map_err(Error::from)
+            });
+
+        Box::new(with_timeout(f, self.request_timeout))
     }
 
-    pub fn fetch(&self, peer_uri: &str, container: &str, uri: &str) -> FutureFetch {
+    pub fn fetch(
+        &self,
+        peer_uri: &str,
+        container: &str,
+        uri: &str,
+        headers: &header::HeaderMap,
+    ) -> FutureFetch {
         let uri = format!("{}/{}", peer_uri, uri);
 
         trace!(
@@ -87,13 +360,21 @@ impl Client {
             peer_uri,
             uri
         );
-        let request = Request::builder()
+        let mut request = Request::builder();
+        request
             .method(Method::GET)
             .uri(uri)
             .header(header::HOST, container.to_string())
             .header("x-naught-sender", self.sender.clone())
-            .header("x-naught-redirect", "false")
-            .body(Body::empty());
+            .header("x-naught-redirect", "false");
+
+        for name in FORWARD_HEADERS {
+            if let Some(value) = headers.get(name) {
+                request.header(name, value.clone());
+            }
+        }
+
+        let request = request.body(Body::empty());
 
         let request = match request {
             Ok(request) => request,
@@ -104,38 +385,54 @@ impl Client {
 
         let responder = peer_uri.to_string();
 
-        // TODO(indutny): timeout
-        let f = self
-            .client
-            .request(request)
-            .from_err::<Error>()
-            .and_then(|response| {
-                if response.status().is_success() {
-                    Ok(response)
-                } else {
-                    Err(Error::NotFound)
-                }
-            })
-            .map(move |response| {
-                let (parts, body) = response.into_parts();
-
-                let mime = parts
-                    .headers
-                    .get(hyper::header::CONTENT_TYPE)
-                    .map(|val| val.to_str().unwrap_or("unknown"))
-                    .unwrap_or("unknown")
-                    .to_string();
-                response::Fetch {
-                    peer: responder,
-                    mime,
-                    body,
-                }
-            });
+        let idle_timeout = self.idle_timeout;
+        let f = send_tracked(
+            &self.health,
+            peer_uri,
+            send(&self.client, request, self.request_timeout),
+        )
+        .and_then(|response| {
+            let status = response.status();
+            if status.is_success()
+                || status.is_redirection()
+                || status == StatusCode::RANGE_NOT_SATISFIABLE
+                || (status == StatusCode::NOT_FOUND
+                    && response.headers().contains_key(NOT_FOUND_PAGE))
+            {
+                Ok(response)
+            } else {
+                Err(Error::NotFound)
+            }
+        })
+        .map(move |response| {
+            let (parts, body) = response.into_parts();
+
+            let mime = parts
+                .headers
+                .get(hyper::header::CONTENT_TYPE)
+                .map(|val| val.to_str().unwrap_or("unknown"))
+                .unwrap_or("unknown")
+                .to_string();
+            let mut headers = parts.headers;
+            for name in SKIP_HEADERS {
+                headers.remove(name);
+            }
+            headers.remove("x-naught-sender");
+            headers.remove(NOT_FOUND_PAGE);
+
+            response::Fetch {
+                peer: responder,
+                status: parts.status,
+                mime,
+                headers,
+                body: with_idle_timeout(body, idle_timeout),
+            }
+        });
         Box::new(f)
     }
 
-    pub fn store(&self, peer_uri: &str, container: &str, data: &Data) -> FutureEmpty {
-        trace!("store remote container: {} peer: {}", container, peer_uri);
+    pub fn peek(&self, peer_uri: &str, container: &str) -> FutureEmpty {
+        trace!("peek remote container: {} peer: {}", container, peer_uri);
 
         let peek = Request::builder()
             .method(Method::HEAD)
@@ -145,22 +442,74 @@ impl Client {
             .header("x-naught-redirect", "false")
             .body(Body::empty());
 
-        // TODO(indutny): lazy body?
-        let store = Request::builder()
+        let peek = match peek {
+            Ok(peek) => peek,
+            Err(err) => {
+                return Box::new(future::err(Error::from(err)));
+            }
+        };
+
+        let f = send_tracked(
+            &self.health,
+            peer_uri,
+            send(&self.client, peek, self.request_timeout),
+        )
+        .and_then(|response| {
+            if response.status().is_success() {
+                Ok(())
+            } else {
+                Err(Error::NotFound)
+            }
+        });
+        Box::new(f)
+    }
+
+    pub fn store(&self, peer_uri: &str, container: &str, data: &Data) -> FutureEmpty {
+        let client = self.clone();
+        let peer_uri = peer_uri.to_string();
+        let container = container.to_string();
+        let blob = data.blob().clone();
+        let archive = data.archive();
+
+        let store = move || client.store_once(&peer_uri, &container, &blob, archive);
+        retry(Arc::new(store), 0, self.store_attempts, self.retry_backoff)
+    }
+
+    fn store_once(
+        &self,
+        peer_uri: &str,
+        container: &str,
+        blob: &Bytes,
+        archive: Archive,
+    ) -> FutureEmpty {
+        trace!("store remote container: {} peer: {}", container, peer_uri);
+
+        let mut store = Request::builder();
+        store
             .method(Method::PUT)
             .uri(format!("{}/_container", peer_uri))
             .header(header::AUTHORIZATION, self.auth.clone())
             .header(header::ACCEPT, "application/json")
             .header("x-naught-sender", self.sender.to_string())
-            .header("x-naught-redirect", "false")
-            .body(Body::from(Vec::from(data)));
+            .header("x-naught-redirect", "false");
 
-        let peek = match peek {
-            Ok(peek) => peek,
-            Err(err) => {
-                return Box::new(future::err(Error::from(err)));
+        // Peers receive the blob in the uploaded form
+        match archive {
+            Archive::Zip => {
+                store.header(header::CONTENT_TYPE, "application/zip");
             }
-        };
+            Archive::Tar(compression) => {
+                if let Some(encoding) = compression.content_encoding() {
+                    store.header(header::CONTENT_ENCODING, encoding);
+                }
+            }
+        }
+
+        // Blob is shared with `Data`, not copied. Upload of a large blob may
+        // take longer than `request_timeout`, so the timeout only applies
+        // between chunks and to the response after the last one.
+        let progress = Arc::new(Mutex::new(Instant::now()));
+        let store = store.body(progress_body(blob, progress.clone()));
 
         let store = match store {
             Ok(store) => store,
@@ -169,17 +518,7 @@ impl Client {
             }
         };
 
-        let peek = self
-            .client
-            .request(peek)
-            .from_err::<Error>()
-            .and_then(|response| {
-                if response.status().is_success() {
-                    Ok(())
-                } else {
-                    Err(Error::NotFound)
-                }
-            });
+        let peek = self.peek(peer_uri, container);
 
         // TODO(indutny): excessive cloning?
         let debug_uri = format!("{}/{}", peer_uri, container);
@@ -192,16 +531,143 @@ impl Client {
             }
         };
 
-        let store = self
-            .client
-            .request(store)
-            .from_err::<Error>()
-            .and_then(on_store_response);
+        let client = self.client.clone();
+        let health = self.health.clone();
+        let peer_uri = peer_uri.to_string();
+        let request_timeout = self.request_timeout;
+        let store = self.replications.acquire().and_then(move |permit| {
+            let f = IdleTimeout::new(
+                client.request(store).from_err::<Error>(),
+                progress,
+                request_timeout,
+            );
+            send_tracked(&health, &peer_uri, f)
+                .then(move |response| {
+                    drop(permit);
+                    response
+                })
+                .and_then(on_store_response)
+        });
 
         let peek_or_store = peek.or_else(move |_| store);
 
-        // TODO(indutny): timeout
-        // TODO(indutny): retry?
         Box::new(peek_or_store)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::runtime::current_thread::Runtime;
+
+    use crate::hedge::Hedged;
+
+    #[test]
+    fn it_should_retry_with_backoff() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let f = {
+            let calls = calls.clone();
+            move || -> FutureEmpty {
+                if calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                    Box::new(future::err(Error::Timeout))
+                } else {
+                    Box::new(future::ok(()))
+                }
+            }
+        };
+
+        let mut runtime = Runtime::new().expect("runtime to start");
+        let f = Arc::new(f);
+        runtime
+            .block_on(retry(f.clone(), 0, 3, Duration::from_millis(1)))
+            .expect("third attempt to succeed");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        calls.store(0, Ordering::SeqCst);
+        assert!(runtime
+            .block_on(retry(f, 0, 2, Duration::from_millis(1)))
+            .is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let backoff = Duration::from_millis(250);
+        assert_eq!(max_retry_delay(backoff, 2), Duration::from_secs(1));
+        assert_eq!(max_retry_delay(backoff, 31), MAX_RETRY_DELAY);
+        assert_eq!(max_retry_delay(backoff, 64), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn it_should_start_timeout_on_first_poll() {
+        let mut runtime = Runtime::new().expect("runtime to start");
+
+        // Second peer starts only after the first one has timed out, and
+        // still gets the full timeout
+        let timeout = Duration::from_millis(100);
+        let hang: FutureEmpty = Box::new(with_timeout(future::empty(), timeout));
+        let answer: FutureEmpty = Box::new(with_timeout(
+            Delay::new(Instant::now() + Duration::from_millis(150)).map_err(|_| Error::TimerError),
+            timeout,
+        ));
+        runtime
+            .block_on(Hedged::new(vec![hang, answer], None))
+            .expect("second peer to answer");
+    }
+
+    #[test]
+    fn it_should_start_hedge_timeout_on_launch() {
+        let mut runtime = Runtime::new().expect("runtime to start");
+
+        // Hedge launched after 50ms answers 80ms later, past the deadline of
+        // the first request but within its own
+        let timeout = Duration::from_millis(100);
+        let hang: FutureEmpty = Box::new(with_timeout(future::empty(), timeout));
+        let answer: FutureEmpty = Box::new(with_timeout(
+            future::lazy(|| {
+                Delay::new(Instant::now() + Duration::from_millis(80))
+                    .map_err(|_| Error::TimerError)
+            }),
+            timeout,
+        ));
+        runtime
+            .block_on(Hedged::new(
+                vec![hang, answer],
+                Some(Duration::from_millis(50)),
+            ))
+            .expect("hedge to answer");
+    }
+
+    #[test]
+    fn it_should_extend_idle_timeout_on_progress() {
+        let mut runtime = Runtime::new().expect("runtime to start");
+
+        let timeout = Duration::from_millis(50);
+        let upload = || {
+            Delay::new(Instant::now() + Duration::from_millis(150)).map_err(|_| Error::TimerError)
+        };
+
+        // Upload takes longer than the timeout, but progresses every 10ms
+        let progress = Arc::new(Mutex::new(Instant::now()));
+        let touch = {
+            let progress = progress.clone();
+            tokio::timer::Interval::new_interval(Duration::from_millis(10))
+                .take(20)
+                .for_each(move |_| {
+                    *progress.lock().expect("lock to acquire") = Instant::now();
+                    Ok(())
+                })
+                .map_err(|_| Error::TimerError)
+        };
+        runtime
+            .block_on(IdleTimeout::new(upload(), progress, timeout).join(touch))
+            .expect("upload to complete");
+
+        // Stalled upload times out
+        let progress = Arc::new(Mutex::new(Instant::now()));
+        match runtime.block_on(IdleTimeout::new(upload(), progress, timeout)) {
+            Err(Error::Timeout) => (),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -1,8 +1,14 @@
+extern crate hyper;
 extern crate serde;
 
+use std::convert::TryFrom;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
+use hyper::header::HeaderValue;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::error::Error;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PingEvery {
@@ -24,16 +30,32 @@ pub struct Config {
     // Number of copies of each value
     pub replicate: u32,
 
+    // Relative capacity of this node, it gets a share of containers
+    // proportional to it
+    pub weight: f64,
+
     // Initial peer uris
     pub initial_peers: Vec<String>,
 
     // How often to ping other nodes
     pub ping_every: PingEvery,
 
-    // At least one ping should arrive during this time for requests to
-    // be forwarded to this remote node
+    // Removal of silent remote node is delayed by this time on top of
+    // `remove_timeout`
     pub alive_timeout: Duration,
 
+    // Suspicion level of the failure detector at which remote node stops
+    // receiving requests
+    pub phi_threshold: f64,
+
+    // Number of peers asked to ping remote node on our behalf when direct
+    // ping fails
+    pub indirect_probes: usize,
+
+    // Suspected remote node that does not refute suspicion in this time is
+    // considered failed
+    pub suspect_timeout: Duration,
+
     // Remote node would be forgotten after this timeout
     pub remove_timeout: Duration,
 
@@ -42,22 +64,85 @@ pub struct Config {
 
     // How often to rebalance keys between servers
     pub rebalance_every: Duration,
+
+    // Directory to persist containers in, kept in memory if not present
+    pub data_dir: Option<PathBuf>,
+
+    // Bytes of parsed containers kept in memory when `data_dir` is set
+    pub cache_size: u64,
+
+    // Maximum number of bytes taken by stored containers, unlimited if not
+    // present
+    pub memory_budget: Option<u64>,
+
+    // Default `Cache-Control` of served files
+    #[serde(serialize_with = "serialize_header")]
+    pub cache_control: HeaderValue,
+
+    // Maximum size of uploaded container in bytes
+    pub max_upload_size: u64,
+
+    // Limits on archive contents: number of entries, and total size of
+    // decompressed files in bytes
+    pub max_archive_entries: usize,
+    pub max_expanded_size: u64,
+
+    // Maximum number of containers sent to peers at once
+    pub max_replications: usize,
+
+    // Timeouts of requests to peers: for establishing connection, for
+    // receiving response headers, and between chunks of response body
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub idle_timeout: Duration,
+
+    // Attempts made to store container on a peer, and base delay between
+    // them
+    pub store_attempts: u32,
+    pub retry_backoff: Duration,
+
+    // How often under-replicated containers are sent to peers again
+    pub repair_every: Duration,
+
+    // Delay before fetching from the next peer while previous has not
+    // responded yet. Peers are tried one by one if not present.
+    pub hedge_delay: Option<Duration>,
 }
 
 impl Config {
     pub fn new(container_secret: Vec<u8>, hash_seed: (u64, u64)) -> Self {
-        Config::from(UserConfig {
+        Config::try_from(UserConfig {
             https_port: None,
             container_secret,
             hash_seed,
             replicate: None,
+            weight: None,
             initial_peers: vec![],
             ping_every: None,
             alive_timeout: None,
+            phi_threshold: None,
+            indirect_probes: None,
+            suspect_timeout: None,
             remove_timeout: None,
             stable_delay: None,
             rebalance_every: None,
+            data_dir: None,
+            cache_size: None,
+            memory_budget: None,
+            cache_control: None,
+            max_upload_size: None,
+            max_archive_entries: None,
+            max_expanded_size: None,
+            max_replications: None,
+            connect_timeout: None,
+            request_timeout: None,
+            idle_timeout: None,
+            store_attempts: None,
+            retry_backoff: None,
+            repair_every: None,
+            hedge_delay: None,
         })
+        .expect("default config to be valid")
     }
 
     pub fn get_auth(&self) -> String {
@@ -65,13 +150,30 @@ impl Config {
     }
 }
 
-impl From<UserConfig> for Config {
-    fn from(config: UserConfig) -> Self {
-        Self {
+fn serialize_header<S: Serializer>(value: &HeaderValue, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(value.to_str().unwrap_or_default())
+}
+
+impl TryFrom<UserConfig> for Config {
+    type Error = Error;
+
+    fn try_from(config: UserConfig) -> Result<Self, Error> {
+        // Containers are immutable, so are the files in them
+        let cache_control = match config.cache_control {
+            Some(value) => HeaderValue::from_str(&value)
+                .map_err(|_| Error::InvalidConfig(format!("cache_control: {:?}", value)))?,
+            None => HeaderValue::from_static("public, max-age=31536000, immutable"),
+        };
+
+        Ok(Self {
             https_port: config.https_port,
             container_secret: config.container_secret,
             hash_seed: config.hash_seed,
             replicate: config.replicate.unwrap_or(2),
+            weight: config
+                .weight
+                .filter(|weight| weight.is_finite() && *weight > 0.0)
+                .unwrap_or(1.0),
             initial_peers: config.initial_peers,
             ping_every: config.ping_every.unwrap_or_else(|| PingEvery {
                 min: Duration::from_secs(1),
@@ -80,6 +182,11 @@ impl From<UserConfig> for Config {
             alive_timeout: config
                 .alive_timeout
                 .unwrap_or_else(|| Duration::from_secs(6)),
+            phi_threshold: config.phi_threshold.unwrap_or(8.0),
+            indirect_probes: config.indirect_probes.unwrap_or(3),
+            suspect_timeout: config
+                .suspect_timeout
+                .unwrap_or_else(|| Duration::from_secs(10)),
             remove_timeout: config
                 .remove_timeout
                 .unwrap_or_else(|| Duration::from_secs(300)),
@@ -89,7 +196,32 @@ impl From<UserConfig> for Config {
             rebalance_every: config
                 .rebalance_every
                 .unwrap_or_else(|| Duration::from_secs(12)),
-        }
+            data_dir: config.data_dir,
+            cache_size: config.cache_size.unwrap_or(256 * 1024 * 1024),
+            memory_budget: config.memory_budget,
+            cache_control,
+            max_upload_size: config.max_upload_size.unwrap_or(256 * 1024 * 1024),
+            max_archive_entries: config.max_archive_entries.unwrap_or(65_536),
+            max_expanded_size: config.max_expanded_size.unwrap_or(1024 * 1024 * 1024),
+            max_replications: config.max_replications.unwrap_or(4),
+            connect_timeout: config
+                .connect_timeout
+                .unwrap_or_else(|| Duration::from_secs(3)),
+            request_timeout: config
+                .request_timeout
+                .unwrap_or_else(|| Duration::from_secs(10)),
+            idle_timeout: config
+                .idle_timeout
+                .unwrap_or_else(|| Duration::from_secs(10)),
+            store_attempts: std::cmp::max(config.store_attempts.unwrap_or(3), 1),
+            retry_backoff: config
+                .retry_backoff
+                .unwrap_or_else(|| Duration::from_millis(250)),
+            repair_every: config
+                .repair_every
+                .unwrap_or_else(|| Duration::from_secs(5)),
+            hedge_delay: config.hedge_delay,
+        })
     }
 }
 
@@ -107,16 +239,32 @@ pub struct UserConfig {
     // Number of copies of each value
     pub replicate: Option<u32>,
 
+    // Relative capacity of this node, it gets a share of containers
+    // proportional to it
+    pub weight: Option<f64>,
+
     // Initial peer uris
     pub initial_peers: Vec<String>,
 
     // How often to ping other nodes
     pub ping_every: Option<PingEvery>,
 
-    // At least one ping should arrive during this time for requests to
-    // be forwarded to this remote node
+    // Removal of silent remote node is delayed by this time on top of
+    // `remove_timeout`
     pub alive_timeout: Option<Duration>,
 
+    // Suspicion level of the failure detector at which remote node stops
+    // receiving requests
+    pub phi_threshold: Option<f64>,
+
+    // Number of peers asked to ping remote node on our behalf when direct
+    // ping fails
+    pub indirect_probes: Option<usize>,
+
+    // Suspected remote node that does not refute suspicion in this time is
+    // considered failed
+    pub suspect_timeout: Option<Duration>,
+
     // Remote node would be forgotten after this timeout
     pub remove_timeout: Option<Duration>,
 
@@ -125,4 +273,65 @@ pub struct UserConfig {
 
     // How often to rebalance keys between servers
     pub rebalance_every: Option<Duration>,
+
+    // Directory to persist containers in, kept in memory if not present
+    pub data_dir: Option<PathBuf>,
+
+    // Bytes of parsed containers kept in memory when `data_dir` is set
+    pub cache_size: Option<u64>,
+
+    // Maximum number of bytes taken by stored containers, unlimited if not
+    // present
+    pub memory_budget: Option<u64>,
+
+    // Default `Cache-Control` of served files
+    pub cache_control: Option<String>,
+
+    // Maximum size of uploaded container in bytes
+    pub max_upload_size: Option<u64>,
+    pub max_archive_entries: Option<usize>,
+    pub max_expanded_size: Option<u64>,
+
+    // Maximum number of containers sent to peers at once
+    pub max_replications: Option<usize>,
+
+    // Timeouts of requests to peers: for establishing connection, for
+    // receiving response headers, and between chunks of response body
+    pub connect_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+
+    // Attempts made to store container on a peer, and base delay between
+    // them
+    pub store_attempts: Option<u32>,
+    pub retry_backoff: Option<Duration>,
+
+    // How often under-replicated containers are sent to peers again
+    pub repair_every: Option<Duration>,
+
+    // Delay before fetching from the next peer while previous has not
+    // responded yet. Peers are tried one by one if not present.
+    pub hedge_delay: Option<Duration>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_reject_invalid_cache_control() {
+        let config: UserConfig = serde_json::from_str(
+            r#"{
+                "container_secret": [0],
+                "hash_seed": [0, 0],
+                "initial_peers": [],
+                "cache_control": "no-cache\n"
+            }"#,
+        )
+        .expect("config to parse");
+        match Config::try_from(config) {
+            Err(Error::InvalidConfig(_)) => {}
+            _ => panic!("expected invalid config"),
+        }
+    }
 }
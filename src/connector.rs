@@ -0,0 +1,45 @@
+extern crate futures;
+extern crate hyper;
+extern crate tokio;
+
+use std::io;
+use std::time::Duration;
+
+use futures::prelude::*;
+use hyper::client::connect::{Connect, Connected, Destination};
+use tokio::timer::Timeout;
+
+// Wraps connector to fail connections that are not established in time
+pub struct TimeoutConnector<C> {
+    inner: C,
+    timeout: Duration,
+}
+
+impl<C> TimeoutConnector<C> {
+    pub fn new(inner: C, timeout: Duration) -> Self {
+        TimeoutConnector { inner, timeout }
+    }
+}
+
+impl<C> Connect for TimeoutConnector<C>
+where
+    C: Connect,
+    C::Future: 'static,
+{
+    type Transport = C::Transport;
+    type Error = io::Error;
+    type Future = Box<Future<Item = (Self::Transport, Connected), Error = Self::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let f = Timeout::new(self.inner.connect(dst), self.timeout).map_err(|err| {
+            if err.is_elapsed() {
+                io::Error::new(io::ErrorKind::TimedOut, "connect timed out")
+            } else if let Some(err) = err.into_inner() {
+                io::Error::other(err)
+            } else {
+                io::Error::other("connect timer failed")
+            }
+        });
+        Box::new(f)
+    }
+}
@@ -1,68 +1,1400 @@
+extern crate brotli;
+extern crate bytes;
+extern crate flate2;
+extern crate hyper;
 extern crate mime_guess;
+extern crate serde;
+extern crate serde_json;
+extern crate sha2;
 extern crate tar;
+extern crate xz2;
+extern crate zip;
+extern crate zstd;
 
-use std::collections::HashMap;
-use std::io::Read;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use hyper::StatusCode;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
 use crate::error::Error;
+use crate::headers::HeaderRules;
+use crate::redirects::RedirectRules;
+
+// Manifest files, parsed and not served
+const HEADERS_FILE: &str = "_headers";
+const REDIRECTS_FILE: &str = "_redirects";
+const MANIFEST_FILE: &str = "_naught.json";
+
+// Links pointing to other links are followed up to this depth
+const MAX_LINK_DEPTH: usize = 8;
+
+// Tried in order when serving a directory
+const INDEX_FILES: &[&str] = &["index.html", "index.htm"];
+
+// Served with 404 status for missing files, unless overridden in manifest
+const NOT_FOUND_FILE: &str = "404.html";
+
+// Files smaller than this are not worth compressing
+const MIN_COMPRESS_SIZE: usize = 256;
+
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    fn from_ext(ext: &str) -> Option<Self> {
+        match ext {
+            "gz" => Some(Encoding::Gzip),
+            "br" => Some(Encoding::Brotli),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    fn compress(self, content: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(content)?;
+                Ok(encoder.finish()?)
+            }
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(
+                        &mut out,
+                        BROTLI_BUFFER_SIZE,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW,
+                    );
+                    encoder.write_all(content)?;
+                }
+                Ok(out)
+            }
+        }
+    }
 
-// TODO(indutny): compression
+    fn decompress(self, content: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        match self {
+            Encoding::Gzip => GzDecoder::new(content).read_to_end(&mut out)?,
+            Encoding::Brotli => {
+                brotli::Decompressor::new(content, BROTLI_BUFFER_SIZE).read_to_end(&mut out)?
+            }
+        };
+        Ok(out)
+    }
+}
+
+#[derive(Clone)]
 pub struct DataFile {
     pub mime: String,
-    pub content: Vec<u8>,
+    // Slice of the `Data` blob, not present when the container has only
+    // precompressed version of the file
+    pub content: Option<Bytes>,
+    pub gzip: Option<Bytes>,
+    pub brotli: Option<Bytes>,
+    // Hash of the uncompressed contents
+    pub hash: String,
+    pub modified: Option<SystemTime>,
 }
 
-pub struct Data {
+impl DataFile {
+    fn new(mime: String, content: Option<Bytes>, modified: Option<SystemTime>) -> Self {
+        DataFile {
+            mime,
+            content,
+            gzip: None,
+            brotli: None,
+            hash: String::new(),
+            modified,
+        }
+    }
+
+    fn compute_hash(&mut self) {
+        // Precompressed-only files are identified by their first variant
+        let content = self
+            .content
+            .as_ref()
+            .or(self.gzip.as_ref())
+            .or(self.brotli.as_ref());
+        let content = match content {
+            Some(content) => content,
+            None => return,
+        };
+
+        let mut hasher = Sha256::new();
+        hasher.input(&content[..]);
+        self.hash = hasher.result()[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+    }
+
+    // Strong validator of the representation
+    pub fn etag(&self, encoding: Option<Encoding>) -> String {
+        match encoding {
+            Some(encoding) => format!("\"{}-{}\"", self.hash, encoding.as_str()),
+            None => format!("\"{}\"", self.hash),
+        }
+    }
+
+    fn is_compressible(&self) -> bool {
+        let mime = &self.mime;
+        mime.starts_with("text/")
+            || mime.ends_with("javascript")
+            || mime.ends_with("json")
+            || mime.ends_with("xml")
+            || mime == "application/wasm"
+    }
+
+    fn variant(&self, encoding: Encoding) -> &Option<Bytes> {
+        match encoding {
+            Encoding::Gzip => &self.gzip,
+            Encoding::Brotli => &self.brotli,
+        }
+    }
+
+    fn variant_mut(&mut self, encoding: Encoding) -> &mut Option<Bytes> {
+        match encoding {
+            Encoding::Gzip => &mut self.gzip,
+            Encoding::Brotli => &mut self.brotli,
+        }
+    }
+
+    fn compress(&mut self) -> Result<(), Error> {
+        let content = match self.content {
+            Some(ref content) if content.len() >= MIN_COMPRESS_SIZE => content.clone(),
+            _ => return Ok(()),
+        };
+        if !self.is_compressible() {
+            return Ok(());
+        }
+
+        for encoding in &[Encoding::Brotli, Encoding::Gzip] {
+            // Precompressed sibling takes priority
+            if self.variant(*encoding).is_some() {
+                continue;
+            }
+
+            let compressed = encoding.compress(&content)?;
+            if compressed.len() < content.len() {
+                *self.variant_mut(*encoding) = Some(Bytes::from(compressed));
+            }
+        }
+        Ok(())
+    }
+
+    // Number of bytes allocated outside of the `Data` blob
+    fn extra_size(&self) -> usize {
+        self.gzip.as_ref().map(Bytes::len).unwrap_or(0)
+            + self.brotli.as_ref().map(Bytes::len).unwrap_or(0)
+    }
+
+    // Pick representation according to `Accept-Encoding` header value.
+    // Returns content encoding and the body.
+    pub fn encode(&self, accept_encoding: &str) -> Result<(Option<Encoding>, Bytes), Error> {
+        for encoding in &[Encoding::Brotli, Encoding::Gzip] {
+            if let Some(ref compressed) = self.variant(*encoding) {
+                if accepts(accept_encoding, *encoding) {
+                    return Ok((Some(*encoding), compressed.clone()));
+                }
+            }
+        }
+
+        if let Some(ref content) = self.content {
+            return Ok((None, content.clone()));
+        }
+
+        // Only precompressed version is available, decompress on the fly
+        for encoding in &[Encoding::Gzip, Encoding::Brotli] {
+            if let Some(ref compressed) = self.variant(*encoding) {
+                return Ok((None, Bytes::from(encoding.decompress(compressed)?)));
+            }
+        }
+        Err(Error::NotFound)
+    }
+}
+
+// Check if `Accept-Encoding` header value allows `encoding`. Explicit entry
+// for the coding takes precedence over `*`.
+fn accepts(accept_encoding: &str, encoding: Encoding) -> bool {
+    let mut wildcard = None;
+    for coding in accept_encoding.split(',') {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+
+        // `q=0` means "not acceptable"
+        let acceptable = parts
+            .filter_map(|param| {
+                let mut pair = param.splitn(2, '=');
+                match (pair.next(), pair.next()) {
+                    (Some(key), Some(q)) if key.eq_ignore_ascii_case("q") => q.parse::<f32>().ok(),
+                    _ => None,
+                }
+            })
+            .all(|q| q > 0.0);
+
+        if name.eq_ignore_ascii_case(encoding.as_str()) {
+            return acceptable;
+        }
+        if name == "*" {
+            wildcard = Some(acceptable);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+// Compression of the uploaded archive
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    pub fn detect(blob: &[u8]) -> Self {
+        if blob.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if blob.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if blob.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+
+    // Parse `Content-Encoding` header value
+    pub fn from_content_encoding(value: &str) -> Option<Self> {
+        match value.trim() {
+            "" | "identity" => Some(Compression::None),
+            "gzip" | "x-gzip" => Some(Compression::Gzip),
+            "zstd" => Some(Compression::Zstd),
+            "xz" => Some(Compression::Xz),
+            _ => None,
+        }
+    }
+
+    pub fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+            Compression::Xz => Some("xz"),
+        }
+    }
+}
+
+// Convert zip's MS-DOS timestamp to system time
+fn zip_time(time: zip::DateTime) -> SystemTime {
+    let (year, month, day) = (
+        i64::from(time.year()),
+        i64::from(time.month()),
+        i64::from(time.day()),
+    );
+
+    // Days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    let secs = days * 86400
+        + i64::from(time.hour()) * 3600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second());
+    UNIX_EPOCH + Duration::from_secs(std::cmp::max(secs, 0) as u64)
+}
+
+struct ArchiveFile {
+    path: String,
+    content: Bytes,
+    modified: Option<SystemTime>,
+}
+
+// Symbolic or hard link, `target` is relative to the archive root
+struct ArchiveLink {
+    path: String,
+    target: String,
+}
+
+// File of a tar that is not fully received yet, `start` and `end` are
+// offsets in the uncompressed tar
+struct TarFile {
+    path: String,
+    start: usize,
+    end: usize,
+    modified: Option<SystemTime>,
+}
+
+// Limits on archive contents, protect nodes from tar and zip bombs
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_entries: usize,
+    pub max_expanded_size: u64,
+}
+
+impl<'a> From<&'a Config> for Limits {
+    fn from(config: &'a Config) -> Self {
+        Limits {
+            max_entries: config.max_archive_entries,
+            max_expanded_size: config.max_expanded_size,
+        }
+    }
+}
+
+// Read at most `limit` bytes of `reader` into `out`, fail if there are more
+fn read_limited<R: Read>(reader: R, out: &mut Vec<u8>, limit: u64) -> Result<(), Error> {
+    let start = out.len() as u64;
+    reader.take(limit.saturating_add(1)).read_to_end(out)?;
+    if out.len() as u64 - start > limit {
+        return Err(Error::ArchiveTooLarge(format!(
+            "expands beyond {} bytes",
+            limit
+        )));
+    }
+    Ok(())
+}
+
+// Output of the streaming decompressor, fails past `limit` bytes
+struct LimitedWriter {
+    out: Vec<u8>,
+    limit: u64,
+    exceeded: bool,
+}
+
+impl LimitedWriter {
+    fn new(limit: u64) -> Self {
+        LimitedWriter {
+            out: vec![],
+            limit,
+            exceeded: false,
+        }
+    }
+
+    // Decompressor errors caused by the limit are reported as such
+    fn error(&self, err: io::Error) -> Error {
+        if self.exceeded {
+            Error::ArchiveTooLarge(format!("expands beyond {} bytes", self.limit))
+        } else {
+            Error::from(err)
+        }
+    }
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.out.len() + buf.len()) as u64 > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive too large",
+            ));
+        }
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Decompresses the tar as its chunks arrive
+enum TarDecoder {
+    None,
+    Gzip(flate2::write::GzDecoder<LimitedWriter>),
+    Zstd(zstd::stream::write::Decoder<LimitedWriter>),
+    Xz(xz2::write::XzDecoder<LimitedWriter>),
+}
+
+impl TarDecoder {
+    fn new(compression: Compression, limit: u64) -> Result<Self, Error> {
+        let out = LimitedWriter::new(limit);
+        Ok(match compression {
+            Compression::None => TarDecoder::None,
+            Compression::Gzip => TarDecoder::Gzip(flate2::write::GzDecoder::new(out)),
+            Compression::Zstd => TarDecoder::Zstd(zstd::stream::write::Decoder::new(out)?),
+            Compression::Xz => TarDecoder::Xz(xz2::write::XzDecoder::new(out)),
+        })
+    }
+
+    fn writer(&self) -> Option<&LimitedWriter> {
+        match self {
+            TarDecoder::None => None,
+            TarDecoder::Gzip(d) => Some(d.get_ref()),
+            TarDecoder::Zstd(d) => Some(d.get_ref()),
+            TarDecoder::Xz(d) => Some(d.get_ref()),
+        }
+    }
+
+    // Decompressed so far, not present for uncompressed tars
+    fn output(&self) -> Option<&[u8]> {
+        self.writer().map(|writer| &writer.out[..])
+    }
+
+    // Output is flushed, so that entries of the chunk are parsed right away
+    fn write(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        let result = match self {
+            TarDecoder::None => return Ok(()),
+            TarDecoder::Gzip(d) => d.write_all(chunk).and_then(|_| d.flush()),
+            TarDecoder::Zstd(d) => d.write_all(chunk).and_then(|_| d.flush()),
+            TarDecoder::Xz(d) => d.write_all(chunk).and_then(|_| d.flush()),
+        };
+        match (result, self.writer()) {
+            (Err(err), Some(writer)) => Err(writer.error(err)),
+            (Err(err), None) => Err(Error::from(err)),
+            (Ok(()), _) => Ok(()),
+        }
+    }
+
+    fn finish(self) -> Result<Option<Vec<u8>>, Error> {
+        match self {
+            TarDecoder::None => Ok(None),
+            TarDecoder::Gzip(mut d) => match d.try_finish() {
+                Ok(()) => Ok(Some(d.finish()?.out)),
+                Err(err) => Err(d.get_ref().error(err)),
+            },
+            TarDecoder::Zstd(mut d) => match d.flush() {
+                Ok(()) => Ok(Some(d.into_inner().out)),
+                Err(err) => Err(d.get_ref().error(err)),
+            },
+            TarDecoder::Xz(mut d) => match d.finish() {
+                Ok(out) => Ok(Some(out.out)),
+                Err(err) => Err(d.get_ref().error(err)),
+            },
+        }
+    }
+}
+
+// Entries of a tar, parsed as its bytes arrive
+#[derive(Default)]
+struct TarEntries {
+    // Offset of the next entry header
+    next: usize,
+    count: usize,
+    expanded: u64,
+    // End of archive marker is reached
+    done: bool,
+    files: Vec<TarFile>,
+    links: Vec<ArchiveLink>,
+}
+
+impl TarEntries {
+    // Parse entries whose headers are in `tar`. Contents of the files may
+    // still be missing. Until the `last` bytes arrive, tar errors may be
+    // caused by a header cut short, and are not final.
+    fn parse(&mut self, tar: &[u8], limits: Limits, last: bool) -> Result<(), Error> {
+        while !self.done && self.parse_next(tar, limits, last)? {}
+        Ok(())
+    }
+
+    // Returns `false` when there is nothing more to parse yet
+    fn parse_next(&mut self, tar: &[u8], limits: Limits, last: bool) -> Result<bool, Error> {
+        // Contents of the previous entry are not received yet
+        if self.next > tar.len() {
+            return Ok(false);
+        }
+
+        let mut archive = tar::Archive::new(&tar[self.next..]);
+        let entry = match archive.entries()?.next() {
+            // Zero block marks the end, rest of the archive is ignored
+            None if tar.len() - self.next >= 512 => {
+                self.done = true;
+                return Ok(false);
+            }
+            None => return Ok(false),
+            Some(Err(_)) if !last => return Ok(false),
+            Some(entry) => entry?,
+        };
+
+        if self.count >= limits.max_entries {
+            return Err(Error::ArchiveTooLarge(format!(
+                "more than {} entries",
+                limits.max_entries
+            )));
+        }
+        self.count += 1;
+
+        // Extended sparse headers follow the entry header, and are not
+        // counted in `raw_file_position`
+        let mut start = self.next + entry.raw_file_position() as usize;
+        let mut extended = entry.header().as_gnu().map(|gnu| gnu.is_extended()) == Some(true);
+        while extended {
+            extended = tar[start + 504] == 1;
+            start += 512;
+        }
+        let size = entry.header().entry_size()?;
+        self.next = start + ((size as usize + 511) & !511);
+
+        let path = match entry.path()?.to_str() {
+            Some(path) => sanitize_path(path)?,
+            None => return Ok(true),
+        };
+
+        let kind = entry.header().entry_type();
+        if kind.is_symlink() || kind.is_hard_link() {
+            let target = match entry.link_name()? {
+                Some(target) => target.to_str().unwrap_or("").to_string(),
+                None => return Ok(true),
+            };
+
+            // Symbolic links are relative to their own directory, hard
+            // links to the archive root
+            let target = if kind.is_symlink() && !target.starts_with('/') {
+                let dir = path.rfind('/').map(|i| &path[..i]).unwrap_or("");
+                sanitize_path(&join_path(dir, &target))?
+            } else {
+                sanitize_path(&target)?
+            };
+            self.links.push(ArchiveLink { path, target });
+            return Ok(true);
+        }
+
+        // Directories are implied by file paths. Devices and fifos have no
+        // content, and contents of sparse files are not stored
+        // contiguously.
+        if !kind.is_file() && !kind.is_contiguous() {
+            trace!("skipping entry: {} type: {:?}", path, kind);
+            return Ok(true);
+        }
+
+        // Decompression is already limited, but uncompressed tars are not
+        self.expanded = self.expanded.saturating_add(size);
+        if self.expanded > limits.max_expanded_size {
+            return Err(Error::ArchiveTooLarge(format!(
+                "expands beyond {} bytes",
+                limits.max_expanded_size
+            )));
+        }
+
+        let modified = entry
+            .header()
+            .mtime()
+            .ok()
+            .map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime));
+
+        self.files.push(TarFile {
+            path,
+            start,
+            end: start + size as usize,
+            modified,
+        });
+        Ok(true)
+    }
+}
+
+// Parses tar as the upload arrives, so that unsafe and oversized archives
+// are rejected before all of it is received
+pub struct TarParser {
+    compression: Compression,
+    limits: Limits,
     blob: Vec<u8>,
+    decoder: TarDecoder,
+    entries: TarEntries,
+}
+
+impl TarParser {
+    pub fn new(compression: Compression, limits: Limits, capacity: usize) -> Result<Self, Error> {
+        Ok(TarParser {
+            compression,
+            limits,
+            blob: Vec::with_capacity(capacity),
+            decoder: TarDecoder::new(compression, limits.max_expanded_size)?,
+            entries: TarEntries::default(),
+        })
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.blob.extend_from_slice(chunk);
+        self.decoder.write(chunk)?;
+
+        let tar = self.decoder.output().unwrap_or(&self.blob);
+        self.entries.parse(tar, self.limits, false)
+    }
+
+    pub fn finish(self) -> Result<Data, Error> {
+        let blob = Bytes::from(self.blob);
+        let tar = match self.decoder.finish()? {
+            Some(tar) => Bytes::from(tar),
+            None => blob.clone(),
+        };
+        trace!(
+            "archive compression: {:?} size: {} uncompressed: {}",
+            self.compression,
+            blob.len(),
+            tar.len()
+        );
+
+        let mut entries = self.entries;
+        entries.parse(&tar, self.limits, true)?;
+
+        let mut files = vec![];
+        for TarFile {
+            path,
+            start,
+            end,
+            modified,
+        } in entries.files
+        {
+            if end > tar.len() {
+                return Err(Error::IO(format!("truncated tar entry at {}", start)));
+            }
+            files.push(ArchiveFile {
+                path,
+                content: tar.slice(start, end),
+                modified,
+            });
+        }
+
+        let expanded = if self.compression == Compression::None {
+            0
+        } else {
+            tar.len()
+        };
+        Data::from_files(
+            blob,
+            Archive::Tar(self.compression),
+            expanded,
+            files,
+            entries.links,
+        )
+    }
+}
+
+// Upload being received. Zip archives have their directory at the end, so
+// they are parsed only once complete.
+pub enum ArchiveParser {
+    Tar(Box<TarParser>),
+    Zip(Vec<u8>, Limits),
+}
+
+impl ArchiveParser {
+    pub fn new(archive: Archive, limits: Limits, capacity: usize) -> Result<Self, Error> {
+        Ok(match archive {
+            Archive::Tar(compression) => {
+                ArchiveParser::Tar(Box::new(TarParser::new(compression, limits, capacity)?))
+            }
+            Archive::Zip => ArchiveParser::Zip(Vec::with_capacity(capacity), limits),
+        })
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        match self {
+            ArchiveParser::Tar(parser) => parser.push(chunk),
+            ArchiveParser::Zip(blob, _) => {
+                blob.extend_from_slice(chunk);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn finish(self) -> Result<Data, Error> {
+        match self {
+            ArchiveParser::Tar(parser) => parser.finish(),
+            ArchiveParser::Zip(blob, limits) => Data::from_zip(blob, limits),
+        }
+    }
+}
+
+fn get_ext(path: &str) -> &str {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+}
+
+fn get_mime(path: &str) -> String {
+    let ext = get_ext(path);
+    format!("{}", mime_guess::get_mime_type(ext))
+}
+
+// Resolve `.` and `..`, strip leading and duplicate slashes. Paths escaping
+// the archive root are rejected.
+fn sanitize_path(path: &str) -> Result<String, Error> {
+    let mut segments = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err(Error::UnsafePath(path.to_string()));
+                }
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Ok(segments.join("/"))
+}
+
+fn add_parent_dirs(dirs: &mut HashSet<String>, path: &str) {
+    let mut dir = path;
+    while let Some(i) = dir.rfind('/') {
+        dir = &dir[..i];
+        dirs.insert(dir.to_string());
+    }
+}
+
+// Strip `./`, leading and duplicate slashes
+fn normalize_path(path: &str) -> String {
+    path.split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Format of the uploaded blob
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Archive {
+    Tar(Compression),
+    Zip,
+}
+
+impl Archive {
+    pub fn detect(blob: &[u8]) -> Self {
+        if blob.starts_with(b"PK\x03\x04") || blob.starts_with(b"PK\x05\x06") {
+            Archive::Zip
+        } else {
+            Archive::Tar(Compression::detect(blob))
+        }
+    }
+}
+
+// Container settings from `_naught.json`
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Manifest {
+    // Serve index for unknown paths without extension
+    spa: bool,
+    not_found: Option<String>,
+    // Generate listing for directories without index
+    listing: bool,
+}
+
+pub struct Data {
+    // Uploaded form, replicated to other peers as is
+    blob: Bytes,
+    archive: Archive,
+    // Bytes allocated for decompressed archive contents
+    expanded: usize,
     map: HashMap<String, DataFile>,
+    // All directories containing files, root is `""`
+    dirs: HashSet<String>,
+    headers: HeaderRules,
+    redirects: RedirectRules,
+    manifest: Manifest,
 }
 
 impl Data {
-    pub fn from_tar(blob: Vec<u8>) -> Result<Self, Error> {
-        let mut archive = tar::Archive::new(&blob[..]);
-        let mut map = HashMap::new();
-        for entry in archive.entries()? {
-            let mut entry = entry?;
-            let mut content = Vec::with_capacity(entry.header().size()? as usize);
-
-            entry.read_to_end(&mut content)?;
-            let path = entry.header().path()?;
-            let ext = path
-                .extension()
-                .map(|ext| ext.to_str())
-                .unwrap_or(None)
-                .unwrap_or("");
-            let mime = format!("{}", mime_guess::get_mime_type(ext));
-
-            if let Some(path) = path.to_str() {
-                trace!("new file: {} size: {}", path, content.len());
-                let path = path.to_string();
-
-                // Redirect / to /index.html
-                if path == "index.htm" || path == "index.html" {
-                    map.insert(
-                        "".to_string(),
-                        DataFile {
-                            mime: mime.clone(),
-                            content: content.clone(),
-                        },
-                    );
+    pub fn from_archive(blob: Vec<u8>, archive: Archive, limits: Limits) -> Result<Self, Error> {
+        let mut parser = ArchiveParser::new(archive, limits, 0)?;
+        parser.push(&blob)?;
+        parser.finish()
+    }
+
+    pub fn from_tar(blob: Vec<u8>, limits: Limits) -> Result<Self, Error> {
+        let compression = Compression::detect(&blob);
+        Data::from_compressed_tar(blob, compression, limits)
+    }
+
+    pub fn from_compressed_tar(
+        blob: Vec<u8>,
+        compression: Compression,
+        limits: Limits,
+    ) -> Result<Self, Error> {
+        let mut parser = TarParser::new(compression, limits, blob.len())?;
+        parser.push(&blob)?;
+        parser.finish()
+    }
+
+    pub fn from_zip(blob: Vec<u8>, limits: Limits) -> Result<Self, Error> {
+        let blob = Bytes::from(blob);
+        let mut archive = zip::ZipArchive::new(Cursor::new(&blob[..]))?;
+        if archive.len() > limits.max_entries {
+            return Err(Error::ArchiveTooLarge(format!(
+                "more than {} entries",
+                limits.max_entries
+            )));
+        }
+
+        let mut files = vec![];
+        let mut expanded = 0;
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            if file.is_dir() {
+                continue;
+            }
+            let path = sanitize_path(file.name())?;
+
+            // Stored files are sliced from the blob as is
+            let content = if file.compression() == zip::CompressionMethod::Stored {
+                let start = file.data_start() as usize;
+                let end = start + file.size() as usize;
+                if end > blob.len() {
+                    return Err(Error::Zip(format!("truncated zip entry at {}", start)));
                 }
+                blob.slice(start, end)
+            } else {
+                // Declared size is not trusted, each entry may only take what
+                // is left of the budget
+                let mut content = vec![];
+                let remaining = limits.max_expanded_size.saturating_sub(expanded as u64);
+                read_limited(&mut file, &mut content, remaining)?;
+                expanded += content.len();
+                Bytes::from(content)
+            };
+
+            files.push(ArchiveFile {
+                path,
+                content,
+                modified: Some(zip_time(file.last_modified())),
+            });
+        }
+
+        Data::from_files(blob, Archive::Zip, expanded, files, vec![])
+    }
+
+    fn from_files(
+        blob: Bytes,
+        archive: Archive,
+        expanded: usize,
+        files: Vec<ArchiveFile>,
+        mut links: Vec<ArchiveLink>,
+    ) -> Result<Self, Error> {
+        let mut map = HashMap::new();
+        let mut precompressed = vec![];
+        let mut headers = HeaderRules::default();
+        let mut redirects = RedirectRules::default();
+        let mut manifest = Manifest::default();
+        let mut dirs = HashSet::new();
+        dirs.insert(String::new());
+        for ArchiveFile {
+            path,
+            content,
+            modified,
+        } in files
+        {
+            if path.is_empty() {
+                continue;
+            }
+
+            if path == HEADERS_FILE {
+                headers = HeaderRules::parse(&String::from_utf8_lossy(&content))?;
+                continue;
+            }
+            if path == REDIRECTS_FILE {
+                redirects = RedirectRules::parse(&String::from_utf8_lossy(&content))?;
+                continue;
+            }
+            if path == MANIFEST_FILE {
+                manifest = serde_json::from_slice(&content)
+                    .map_err(|err| Error::Manifest(format!("{}: {}", MANIFEST_FILE, err)))?;
+                continue;
+            }
+
+            trace!("new file: {} size: {}", path, content.len());
+            add_parent_dirs(&mut dirs, &path);
+
+            let mime = get_mime(&path);
 
-                map.insert(path, DataFile { mime, content });
+            // `foo.js.gz` is a compressed variant of `foo.js`
+            let ext = get_ext(&path);
+            if let Some(encoding) = Encoding::from_ext(ext) {
+                let original = path[..path.len() - ext.len() - 1].to_string();
+                precompressed.push((original, encoding, content.clone(), modified));
             }
+
+            map.insert(path, DataFile::new(mime, Some(content), modified));
+        }
+
+        for (path, encoding, content, modified) in precompressed {
+            trace!("precompressed file: {} encoding: {:?}", path, encoding);
+            let mime = get_mime(&path);
+            let file = map
+                .entry(path)
+                .or_insert_with(|| DataFile::new(mime, None, modified));
+            *file.variant_mut(encoding) = Some(content);
         }
-        Ok(Data { blob, map })
+
+        for file in map.values_mut() {
+            file.compute_hash();
+            file.compress()?;
+        }
+
+        // Links are served as copies of their targets
+        for _ in 0..MAX_LINK_DEPTH {
+            let mut pending = vec![];
+            for link in links {
+                match map.get(&link.target).cloned() {
+                    Some(file) => {
+                        trace!("new link: {} target: {}", link.path, link.target);
+                        add_parent_dirs(&mut dirs, &link.path);
+                        map.insert(link.path, file);
+                    }
+                    None => pending.push(link),
+                }
+            }
+            links = pending;
+        }
+        for link in links {
+            trace!("dangling link: {} target: {}", link.path, link.target);
+        }
+
+        Ok(Data {
+            blob,
+            archive,
+            expanded,
+            map,
+            dirs,
+            headers,
+            redirects,
+            manifest,
+        })
+    }
+
+    pub fn blob(&self) -> &Bytes {
+        &self.blob
+    }
+
+    pub fn archive(&self) -> Archive {
+        self.archive
+    }
+
+    // Number of bytes accounted towards the memory budget. Uncompressed files
+    // are slices of the archive and take no extra space.
+    pub fn size(&self) -> u64 {
+        let compressed: usize = self.map.values().map(DataFile::extra_size).sum();
+        (self.blob.len() + self.expanded + compressed) as u64
+    }
+
+    pub(crate) fn header_rules(&self) -> &HeaderRules {
+        &self.headers
+    }
+
+    pub(crate) fn redirect_rules(&self) -> &RedirectRules {
+        &self.redirects
     }
 
+    // `dir/` and `` are served from the index file of the directory
     pub fn serve(&self, uri: &str) -> Option<&DataFile> {
         trace!("serving uri: {}", uri);
-        self.map.get(uri)
+        let path = normalize_path(uri);
+        if !uri.is_empty() && !uri.ends_with('/') {
+            return self.map.get(&path);
+        }
+
+        INDEX_FILES
+            .iter()
+            .filter_map(|index| self.map.get(&join_path(&path, index)))
+            .next()
+    }
+
+    pub fn is_directory(&self, uri: &str) -> bool {
+        self.dirs.contains(&normalize_path(uri))
+    }
+
+    // Generated page with directory contents, if enabled in manifest
+    pub fn listing(&self, uri: &str) -> Option<DataFile> {
+        if !self.manifest.listing || !self.is_directory(uri) {
+            return None;
+        }
+
+        let dir = normalize_path(uri);
+        let prefix = if dir.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", dir)
+        };
+
+        // Directories first, then files
+        let mut dirs = BTreeSet::new();
+        let mut files = BTreeSet::new();
+        for path in self.map.keys() {
+            if !path.starts_with(&prefix) {
+                continue;
+            }
+            let rest = &path[prefix.len()..];
+            match rest.find('/') {
+                Some(i) => dirs.insert(format!("{}/", &rest[..i])),
+                None => files.insert(rest.to_string()),
+            };
+        }
+
+        let title = escape_html(&format!("/{}", prefix));
+        let mut html = String::from("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\">");
+        html.push_str(&format!(
+            "<title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+            title
+        ));
+        if !dir.is_empty() {
+            html.push_str("<li><a href=\"../\">../</a></li>\n");
+        }
+        for name in dirs.iter().chain(files.iter()) {
+            let name = escape_html(name);
+            html.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", name, name));
+        }
+        html.push_str("</ul>\n</body>\n</html>\n");
+
+        let mut file = DataFile::new(get_mime("index.html"), Some(Bytes::from(html)), None);
+        file.compute_hash();
+        Some(file)
+    }
+
+    // File to serve in place of missing `uri`
+    pub fn fallback(&self, uri: &str) -> Option<(StatusCode, &DataFile)> {
+        let is_asset = uri.rsplit('/').next().unwrap_or("").contains('.');
+        if self.manifest.spa && !is_asset {
+            if let Some(index) = self.serve("") {
+                trace!("spa fallback for uri: {}", uri);
+                return Some((StatusCode::OK, index));
+            }
+        }
+
+        let page = match self.manifest.not_found {
+            Some(ref page) => page.trim_start_matches('/'),
+            None => NOT_FOUND_FILE,
+        };
+        self.map.get(page).map(|file| (StatusCode::NOT_FOUND, file))
     }
 }
 
 impl From<&Data> for Vec<u8> {
     fn from(d: &Data) -> Self {
-        d.blob.clone()
+        d.blob.to_vec()
+    }
+}
+
+// Test fixtures, shared with other modules
+
+#[cfg(test)]
+pub fn tar_blob(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mtime(1_000_000_000);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, path, *content)
+            .expect("append to succeed");
+    }
+    builder.into_inner().expect("tar to finish")
+}
+
+#[cfg(test)]
+pub fn tar_data(files: &[(&str, &[u8])]) -> Data {
+    let limits = Limits::from(&Config::new(vec![0], (0, 0)));
+    Data::from_tar(tar_blob(files), limits).expect("tar to parse")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits::from(&Config::new(vec![0], (0, 0)))
+    }
+
+    #[test]
+    fn it_should_parse_compressed_tar() {
+        let tar = tar_blob(&[("index.html", b"hello")]);
+        let gzip = Encoding::Gzip.compress(&tar).unwrap();
+        assert_eq!(Compression::detect(&gzip), Compression::Gzip);
+
+        let data = Data::from_tar(gzip.clone(), limits()).unwrap();
+        assert_eq!(data.archive(), Archive::Tar(Compression::Gzip));
+        assert_eq!(&data.blob()[..], &gzip[..]);
+
+        let (_, body) = data.serve("").unwrap().encode("").unwrap();
+        assert_eq!(&body[..], b"hello");
+    }
+
+    #[test]
+    fn it_should_parse_tar_in_chunks() {
+        let script = "console.log('hello');\n".repeat(64);
+        let tar = tar_blob(&[("a.js", script.as_bytes()), ("b.txt", b"world")]);
+        let gzip = Encoding::Gzip.compress(&tar).unwrap();
+
+        let mut parser = TarParser::new(Compression::Gzip, limits(), 0).unwrap();
+        for chunk in gzip.chunks(7) {
+            parser.push(chunk).unwrap();
+        }
+        let data = parser.finish().unwrap();
+        let (_, body) = data.serve("b.txt").unwrap().encode("").unwrap();
+        assert_eq!(&body[..], b"world");
+
+        // Decompressed tar is over the limit before the upload is complete
+        let mut limits = limits();
+        limits.max_expanded_size = 1024;
+        let mut parser = TarParser::new(Compression::Gzip, limits, 0).unwrap();
+        match parser.push(&gzip) {
+            Err(Error::ArchiveTooLarge(_)) => {}
+            _ => panic!("expected archive to be too large"),
+        }
+    }
+
+    #[test]
+    fn it_should_parse_zip() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let stored =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        writer.start_file("index.html", stored).unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer
+            .start_file("app.js", zip::write::FileOptions::default())
+            .unwrap();
+        writer.write_all(b"console.log(1);").unwrap();
+        let blob = writer.finish().unwrap().into_inner();
+
+        assert_eq!(Archive::detect(&blob), Archive::Zip);
+        let data = Data::from_archive(blob, Archive::Zip, limits()).unwrap();
+
+        let (_, body) = data.serve("").unwrap().encode("").unwrap();
+        assert_eq!(&body[..], b"hello");
+        let file = data.serve("app.js").unwrap();
+        assert_eq!(file.mime, "application/javascript");
+        assert_eq!(&file.encode("").unwrap().1[..], b"console.log(1);");
+    }
+
+    #[test]
+    fn it_should_limit_expanded_zip() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for name in &["a.txt", "b.txt"] {
+            writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(&[b'a'; 600]).unwrap();
+        }
+        let blob = writer.finish().unwrap().into_inner();
+
+        let mut limits = limits();
+        limits.max_expanded_size = 1000;
+        match Data::from_zip(blob.clone(), limits) {
+            Err(Error::ArchiveTooLarge(_)) => {}
+            _ => panic!("expected archive to be too large"),
+        }
+
+        limits.max_expanded_size = 1200;
+        assert!(Data::from_zip(blob, limits).is_ok());
+    }
+
+    #[test]
+    fn it_should_limit_expanded_tar() {
+        let tar = tar_blob(&[("a.txt", &[b'a'; 600]), ("b.txt", &[b'b'; 600])]);
+
+        let mut limits = limits();
+        limits.max_expanded_size = 1000;
+        match Data::from_tar(tar.clone(), limits) {
+            Err(Error::ArchiveTooLarge(_)) => {}
+            _ => panic!("expected archive to be too large"),
+        }
+
+        limits.max_expanded_size = 1200;
+        assert!(Data::from_tar(tar, limits).is_ok());
+    }
+
+    #[test]
+    fn it_should_negotiate_encoding() {
+        let script = "console.log('hello');\n".repeat(64);
+        let data = Data::from_tar(tar_blob(&[("app.js", script.as_bytes())]), limits()).unwrap();
+        let file = data.serve("app.js").expect("file to be present");
+
+        let (encoding, _) = file.encode("gzip, deflate, br").unwrap();
+        assert_eq!(encoding, Some(Encoding::Brotli));
+
+        let (encoding, _) = file.encode("br;q=0, gzip").unwrap();
+        assert_eq!(encoding, Some(Encoding::Gzip));
+
+        // Explicit refusal wins over the wildcard
+        let (encoding, _) = file.encode("BR;q=0, *").unwrap();
+        assert_eq!(encoding, Some(Encoding::Gzip));
+
+        let (encoding, _) = file.encode("GZip").unwrap();
+        assert_eq!(encoding, Some(Encoding::Gzip));
+
+        let (encoding, body) = file.encode("").unwrap();
+        assert_eq!(encoding, None);
+        assert_eq!(&body[..], script.as_bytes());
+    }
+
+    #[test]
+    fn it_should_use_precompressed_siblings() {
+        let compressed = Encoding::Gzip.compress(b"body { }").unwrap();
+        let data = Data::from_tar(tar_blob(&[("style.css.gz", &compressed)]), limits()).unwrap();
+        let file = data.serve("style.css").expect("file to be present");
+        assert_eq!(file.mime, "text/css");
+
+        let (encoding, body) = file.encode("gzip").unwrap();
+        assert_eq!(encoding, Some(Encoding::Gzip));
+        assert_eq!(&body[..], &compressed[..]);
+
+        let (encoding, body) = file.encode("identity").unwrap();
+        assert_eq!(encoding, None);
+        assert_eq!(&body[..], b"body { }");
+    }
+
+    #[test]
+    fn it_should_fall_back_to_not_found_page() {
+        let data = Data::from_tar(
+            tar_blob(&[("index.html", b"index"), ("404.html", b"missing")]),
+            limits(),
+        )
+        .unwrap();
+        let (status, file) = data.fallback("app/settings").unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(&file.encode("").unwrap().1[..], b"missing");
+
+        let data = Data::from_tar(
+            tar_blob(&[
+                ("index.html", b"index"),
+                ("_naught.json", br#"{ "spa": true }"#),
+            ]),
+            limits(),
+        )
+        .unwrap();
+        assert!(data.serve("_naught.json").is_none());
+        let (status, file) = data.fallback("app/settings").unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&file.encode("").unwrap().1[..], b"index");
+        assert!(data.fallback("app/missing.js").is_none());
+    }
+
+    #[test]
+    fn it_should_resolve_directories() {
+        let data = Data::from_tar(
+            tar_blob(&[
+                ("./docs//index.html", b"docs"),
+                ("docs/api/intro.html", b"intro"),
+                ("_naught.json", br#"{ "listing": true }"#),
+            ]),
+            limits(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            &data.serve("docs/").unwrap().encode("").unwrap().1[..],
+            b"docs"
+        );
+        assert!(data.serve("docs/index.html").is_some());
+        assert!(data.serve("docs").is_none());
+        assert!(data.is_directory("docs"));
+        assert!(data.is_directory("docs/api/"));
+        assert!(!data.is_directory("docs/index.html"));
+
+        assert!(data.serve("docs/api/").is_none());
+        let listing = data.listing("docs/api/").unwrap();
+        let html = listing.encode("").unwrap().1;
+        let html = String::from_utf8_lossy(&html);
+        assert!(html.contains("<a href=\"intro.html\">"));
+        assert!(html.contains("<a href=\"../\">"));
+
+        let listing = data.listing("").unwrap();
+        let html = listing.encode("").unwrap().1;
+        assert!(String::from_utf8_lossy(&html).contains("<a href=\"docs/\">"));
+    }
+
+    #[test]
+    fn it_should_resolve_links() {
+        let mut builder = tar::Builder::new(Vec::new());
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_cksum();
+        builder.append_data(&mut header, "docs/", &b""[..]).unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "docs/index.html", &b"hello"[..])
+            .unwrap();
+
+        for (kind, path, target) in &[
+            (
+                tar::EntryType::Symlink,
+                "latest/index.html",
+                "../docs/index.html",
+            ),
+            (tar::EntryType::Link, "copy.html", "docs/index.html"),
+            (tar::EntryType::Symlink, "chain.html", "copy.html"),
+            (tar::EntryType::Symlink, "dangling.html", "missing.html"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(*kind);
+            header.set_size(0);
+            header.set_link_name(target).unwrap();
+            header.set_cksum();
+            builder.append_data(&mut header, path, &b""[..]).unwrap();
+        }
+
+        let data = Data::from_tar(builder.into_inner().unwrap(), limits()).unwrap();
+        assert!(data.serve("docs").is_none());
+        assert!(data.is_directory("docs"));
+        for path in &["latest/index.html", "copy.html", "chain.html"] {
+            let file = data.serve(path).expect("link to resolve");
+            assert_eq!(&file.encode("").unwrap().1[..], b"hello");
+        }
+        assert!(data.is_directory("latest"));
+        assert!(data.serve("dangling.html").is_none());
+    }
+
+    #[test]
+    fn it_should_reject_unsafe_paths() {
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..9].copy_from_slice(b"../passwd");
+        header.set_size(0);
+        header.set_cksum();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, &b""[..]).unwrap();
+        match Data::from_tar(builder.into_inner().unwrap(), limits()) {
+            Err(Error::UnsafePath(_)) => {}
+            _ => panic!("expected unsafe path error"),
+        }
+
+        assert_eq!(sanitize_path("/a/./b//../c").unwrap(), "a/c");
+        assert!(sanitize_path("a/../../b").is_err());
     }
 }
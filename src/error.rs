@@ -2,6 +2,7 @@ extern crate hmac;
 extern crate hyper;
 extern crate serde;
 extern crate tokio;
+extern crate zip;
 
 use std::error::Error as StdError;
 use std::fmt;
@@ -14,16 +15,27 @@ pub enum Error {
     Hyper(String),
     HyperHTTP(String),
     TimerError,
+    Timeout,
+    CircuitOpen(String),
     NotFound,
     StoreFailed(String),
     PingFailed,
+    DrainFailed,
     BadRequest,
+    UnsupportedEncoding(String),
+    Manifest(String),
+    UnsafePath(String),
+    ArchiveTooLarge(String),
+    UploadTooLarge(u64),
     NonLocalStore(String),
+    OverBudget(String),
     IO(String),
+    InvalidConfig(String),
     Hmac,
     NotAuthorized,
     Unreachable,
     JSON(String),
+    Zip(String),
 }
 
 impl StdError for Error {
@@ -39,15 +51,26 @@ impl fmt::Display for Error {
             Error::Hyper(s) => write!(f, "Hyper: {}", s),
             Error::HyperHTTP(s) => write!(f, "Hyper HTTP: {}", s),
             Error::TimerError => write!(f, "TimerError"),
+            Error::Timeout => write!(f, "Request timed out"),
+            Error::CircuitOpen(s) => write!(f, "Circuit open for peer {}", s),
             Error::Unreachable => write!(f, "Unreachable"),
             Error::NotFound => write!(f, "Resource not found"),
             Error::StoreFailed(s) => write!(f, "Resource {} store failed", s),
             Error::PingFailed => write!(f, "Remote ping failed"),
+            Error::DrainFailed => write!(f, "Remote drain failed"),
             Error::BadRequest => write!(f, "Unsupported request method or uri"),
+            Error::UnsupportedEncoding(s) => write!(f, "Unsupported content encoding: {}", s),
+            Error::Manifest(s) => write!(f, "Invalid manifest: {}", s),
+            Error::UnsafePath(s) => write!(f, "Unsafe path in archive: {}", s),
+            Error::ArchiveTooLarge(s) => write!(f, "Archive too large: {}", s),
+            Error::UploadTooLarge(n) => write!(f, "Upload exceeds {} bytes", n),
             Error::NonLocalStore(s) => write!(f, "Cannot store {} locally", s),
+            Error::OverBudget(s) => write!(f, "Memory budget exceeded, cannot store {}", s),
             Error::IO(s) => write!(f, "IO Error: {}", s),
+            Error::InvalidConfig(s) => write!(f, "Invalid config: {}", s),
             Error::Hmac => write!(f, "Hmac error"),
             Error::JSON(s) => write!(f, "JSON Error: {}", s),
+            Error::Zip(s) => write!(f, "Zip Error: {}", s),
             Error::NotAuthorized => write!(f, "Request not authorized"),
         }
     }
@@ -94,3 +117,9 @@ impl From<hmac::crypto_mac::InvalidKeyLength> for Error {
         Error::Hmac
     }
 }
+
+impl From<zip::result::ZipError> for Error {
+    fn from(err: zip::result::ZipError) -> Self {
+        Error::Zip(format!("{}", err))
+    }
+}
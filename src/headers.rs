@@ -0,0 +1,106 @@
+extern crate hyper;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::error::Error;
+use crate::pattern::Pattern;
+
+// Custom response headers from `_headers` file in the container:
+//
+//   # Comment
+//   /assets/*
+//     Cache-Control: public, max-age=31536000, immutable
+//   /*
+//     X-Frame-Options: DENY
+//
+// Headers from all matching rules are applied in order of appearance.
+
+struct Rule {
+    pattern: Pattern,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+#[derive(Default)]
+pub struct HeaderRules {
+    rules: Vec<Rule>,
+}
+
+impl HeaderRules {
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let mut rules: Vec<Rule> = vec![];
+        for (i, line) in source.lines().enumerate() {
+            let is_indented = line.starts_with(' ') || line.starts_with('\t');
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if !is_indented && line.starts_with('/') {
+                rules.push(Rule {
+                    pattern: Pattern::parse(line),
+                    headers: vec![],
+                });
+                continue;
+            }
+
+            let bad_line = || Error::Manifest(format!("_headers line {}: {}", i + 1, line));
+
+            let rule = rules.last_mut().ok_or_else(bad_line)?;
+            let mut pair = line.splitn(2, ':');
+            let (name, value) = match (pair.next(), pair.next()) {
+                (Some(name), Some(value)) => (name.trim(), value.trim()),
+                _ => return Err(bad_line()),
+            };
+
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| bad_line())?;
+            let value = HeaderValue::from_str(value).map_err(|_| bad_line())?;
+            rule.headers.push((name, value));
+        }
+
+        Ok(HeaderRules { rules })
+    }
+
+    pub fn apply(&self, path: &str, headers: &mut HeaderMap) {
+        for rule in self.rules.iter() {
+            if rule.pattern.matches(path).is_none() {
+                continue;
+            }
+
+            for (name, value) in rule.headers.iter() {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_apply_matching_rules() {
+        let rules = HeaderRules::parse(
+            "# Long-lived assets\n\
+             /assets/*\n  \
+               Cache-Control: public, max-age=31536000\n\
+             \n\
+             /*\n  \
+               X-Frame-Options: DENY\n  \
+               Access-Control-Allow-Origin: *\n",
+        )
+        .expect("rules to parse");
+
+        let mut headers = HeaderMap::new();
+        rules.apply("/assets/app.js", &mut headers);
+        assert_eq!(headers["cache-control"], "public, max-age=31536000");
+        assert_eq!(headers["x-frame-options"], "DENY");
+        assert_eq!(headers["access-control-allow-origin"], "*");
+
+        let mut headers = HeaderMap::new();
+        rules.apply("/index.html", &mut headers);
+        assert!(!headers.contains_key("cache-control"));
+
+        assert!(HeaderRules::parse("X-Frame-Options: DENY").is_err());
+        assert!(HeaderRules::parse("/*\n  Bad Header\n").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::message::response;
+
+// Weight of the latest request in moving averages
+const EWMA_ALPHA: f64 = 0.2;
+
+// Consecutive failures that open the circuit, and for how long
+const CIRCUIT_FAILURES: u32 = 5;
+const CIRCUIT_COOLDOWN: Duration = Duration::from_secs(30);
+
+// Peers below this success rate are tried after healthy ones
+const HEALTHY_RATE: f64 = 0.9;
+
+struct PeerHealth {
+    success_rate: f64,
+    // Seconds
+    latency: f64,
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Default for PeerHealth {
+    fn default() -> Self {
+        PeerHealth {
+            success_rate: 1.0,
+            latency: 0.0,
+            failures: 0,
+            open_until: None,
+        }
+    }
+}
+
+impl PeerHealth {
+    fn is_open(&self, now: Instant) -> bool {
+        self.open_until.map(|until| now < until).unwrap_or(false)
+    }
+}
+
+// Request statistics of peers, shared by all clones of `Client`
+#[derive(Clone, Default)]
+pub struct Health {
+    peers: Arc<Mutex<HashMap<String, PeerHealth>>>,
+}
+
+impl Health {
+    pub fn record(&self, peer_uri: &str, success: bool, latency: Duration) {
+        let mut peers = self.peers.lock().expect("lock to acquire");
+        let health = peers.entry(peer_uri.to_string()).or_default();
+
+        let sample = if success { 1.0 } else { 0.0 };
+        health.success_rate += EWMA_ALPHA * (sample - health.success_rate);
+        health.latency += EWMA_ALPHA * (latency.as_secs_f64() - health.latency);
+
+        if success {
+            health.failures = 0;
+            health.open_until = None;
+            return;
+        }
+
+        // After the cooldown a single failed trial opens the circuit again
+        health.failures += 1;
+        if health.failures >= CIRCUIT_FAILURES {
+            trace!("circuit open for peer: {}", peer_uri);
+            health.open_until = Some(Instant::now() + CIRCUIT_COOLDOWN);
+        }
+    }
+
+    // Whether requests to the peer should be attempted at all. After the
+    // cooldown the circuit is half open: a single trial is let through, and
+    // the next one only if it does not complete within another cooldown.
+    pub fn allows(&self, peer_uri: &str) -> bool {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().expect("lock to acquire");
+        let health = match peers.get_mut(peer_uri) {
+            Some(health) => health,
+            None => return true,
+        };
+
+        match health.open_until {
+            None => true,
+            Some(until) if now < until => false,
+            Some(_) => {
+                trace!("trial request to peer: {}", peer_uri);
+                health.open_until = Some(now + CIRCUIT_COOLDOWN);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&self, peer_uri: &str) {
+        self.peers.lock().expect("lock to acquire").remove(peer_uri);
+    }
+
+    // Lower is better: healthy, degraded, circuit open
+    pub fn rank(&self, peer_uri: &str) -> u8 {
+        let peers = self.peers.lock().expect("lock to acquire");
+        match peers.get(peer_uri) {
+            Some(health) if health.is_open(Instant::now()) => 2,
+            Some(health) if health.success_rate < HEALTHY_RATE => 1,
+            _ => 0,
+        }
+    }
+
+    pub fn scores(&self) -> HashMap<String, response::Health> {
+        let now = Instant::now();
+        let peers = self.peers.lock().expect("lock to acquire");
+        peers
+            .iter()
+            .map(|(uri, health)| {
+                (
+                    uri.clone(),
+                    response::Health {
+                        success_rate: health.success_rate,
+                        latency_ms: health.latency * 1000.0,
+                        failures: health.failures,
+                        circuit_open: health.is_open(now),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_open_circuit_on_failures() {
+        let health = Health::default();
+        let latency = Duration::from_millis(10);
+
+        health.record("a", true, latency);
+        assert_eq!(health.rank("a"), 0);
+        assert_eq!(health.rank("unknown"), 0);
+
+        for _ in 0..CIRCUIT_FAILURES - 1 {
+            health.record("a", false, latency);
+        }
+        assert!(health.allows("a"));
+        assert_eq!(health.rank("a"), 1);
+
+        health.record("a", false, latency);
+        assert!(!health.allows("a"));
+        assert_eq!(health.rank("a"), 2);
+        assert!(health.scores()["a"].circuit_open);
+
+        // Only one trial after the cooldown
+        health
+            .peers
+            .lock()
+            .expect("lock to acquire")
+            .get_mut("a")
+            .unwrap()
+            .open_until = Some(Instant::now());
+        assert!(health.allows("a"));
+        assert!(!health.allows("a"));
+
+        health.record("a", true, latency);
+        assert!(health.allows("a"));
+        assert!(health.allows("a"));
+
+        health.remove("a");
+        assert!(health.scores().is_empty());
+    }
+}
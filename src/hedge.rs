@@ -0,0 +1,149 @@
+extern crate futures;
+extern crate tokio;
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use tokio::timer::Delay;
+
+use crate::error::Error;
+
+type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+
+// Runs futures one after another, starting the next one when the previous
+// fails or (if `delay` is set) takes longer than `delay`. Resolves with the
+// first success, outstanding futures are dropped. Futures are not polled
+// until started, so their timeouts should be set on first poll.
+pub struct Hedged<T> {
+    pending: VecDeque<BoxFuture<T>>,
+    running: Vec<BoxFuture<T>>,
+    delay: Option<Duration>,
+    timer: Option<Delay>,
+    last_error: Error,
+}
+
+impl<T> Hedged<T> {
+    pub fn new(futures: Vec<BoxFuture<T>>, delay: Option<Duration>) -> Self {
+        Hedged {
+            pending: futures.into_iter().collect(),
+            running: vec![],
+            delay,
+            timer: None,
+            last_error: Error::Unreachable,
+        }
+    }
+}
+
+impl<T> Future for Hedged<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<T, Error> {
+        loop {
+            let mut start_next = self.running.is_empty();
+
+            let mut i = 0;
+            while i < self.running.len() {
+                match self.running[i].poll() {
+                    Ok(Async::Ready(item)) => return Ok(Async::Ready(item)),
+                    Ok(Async::NotReady) => i += 1,
+                    Err(err) => {
+                        trace!("hedged future failed due to error: {:?}", err);
+                        self.running.swap_remove(i);
+                        // Skipped peers say less about the failure than
+                        // the ones that were actually tried
+                        let skipped = matches!(err, Error::CircuitOpen(_));
+                        if !skipped || matches!(self.last_error, Error::Unreachable) {
+                            self.last_error = err;
+                        }
+                        start_next = true;
+                    }
+                }
+            }
+
+            if !start_next {
+                if let Some(ref mut timer) = self.timer {
+                    // Timer errors only make the hedge start earlier
+                    start_next = !matches!(timer.poll(), Ok(Async::NotReady));
+                }
+            }
+
+            if !start_next {
+                return Ok(Async::NotReady);
+            }
+
+            match self.pending.pop_front() {
+                Some(f) => {
+                    self.running.push(f);
+                    self.timer = self.delay.map(|delay| Delay::new(Instant::now() + delay));
+                }
+                None => {
+                    self.timer = None;
+                    if self.running.is_empty() {
+                        let err = std::mem::replace(&mut self.last_error, Error::Unreachable);
+                        return Err(err);
+                    }
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn after(ms: u64, result: Result<u32, Error>) -> BoxFuture<u32> {
+        Box::new(
+            Delay::new(Instant::now() + Duration::from_millis(ms))
+                .map_err(|_| Error::TimerError)
+                .and_then(move |_| result),
+        )
+    }
+
+    #[test]
+    fn it_should_hedge_slow_futures() {
+        let mut runtime = Runtime::new().expect("runtime to start");
+
+        // Slow first future is overtaken by the hedge
+        let hedged = Hedged::new(
+            vec![after(1000, Ok(1)), after(0, Ok(2))],
+            Some(Duration::from_millis(10)),
+        );
+        assert_eq!(runtime.block_on(hedged).unwrap(), 2);
+
+        // Failure starts the next one immediately
+        let hedged = Hedged::new(vec![after(0, Err(Error::NotFound)), after(0, Ok(2))], None);
+        assert_eq!(runtime.block_on(hedged).unwrap(), 2);
+
+        let hedged = Hedged::new(
+            vec![
+                Box::new(future::err(Error::NotFound)) as BoxFuture<u32>,
+                Box::new(future::err(Error::Timeout)),
+            ],
+            None,
+        );
+        match runtime.block_on(hedged) {
+            Err(Error::Timeout) => {}
+            _ => panic!("expected last error"),
+        }
+
+        // Open circuit does not hide the error of a tried peer
+        let hedged = Hedged::new(
+            vec![
+                Box::new(future::err(Error::Timeout)) as BoxFuture<u32>,
+                Box::new(future::err(Error::CircuitOpen("peer".to_string()))),
+            ],
+            None,
+        );
+        match runtime.block_on(hedged) {
+            Err(Error::Timeout) => {}
+            _ => panic!("expected error of tried peer"),
+        }
+    }
+}
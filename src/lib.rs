@@ -2,13 +2,23 @@
 extern crate log;
 
 pub mod config;
+pub mod data;
+pub mod error;
 pub mod node;
 pub mod server;
+pub mod store;
 
 mod client;
-mod data;
-mod error;
+mod connector;
+mod headers;
+mod health;
+mod hedge;
+mod limiter;
 mod message;
+mod pattern;
 mod peer;
+mod range;
+mod redirects;
 mod resource;
+mod serve;
 mod service;
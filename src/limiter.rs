@@ -0,0 +1,108 @@
+extern crate futures;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures::future;
+use futures::prelude::*;
+use futures::sync::oneshot;
+
+use crate::error::Error;
+
+type FuturePermit = Box<Future<Item = Permit, Error = Error> + Send>;
+
+struct State {
+    available: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+// Caps the number of concurrently running operations. Waiters are served in
+// FIFO order.
+#[derive(Clone)]
+pub struct Limiter {
+    state: Arc<Mutex<State>>,
+}
+
+// Releases its slot on drop
+pub struct Permit {
+    state: Arc<Mutex<State>>,
+}
+
+impl Limiter {
+    pub fn new(limit: usize) -> Self {
+        Limiter {
+            state: Arc::new(Mutex::new(State {
+                available: limit,
+                waiters: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn acquire(&self) -> FuturePermit {
+        let mut state = self.state.lock().expect("lock to acquire");
+        if state.available > 0 {
+            state.available -= 1;
+            return Box::new(future::ok(Permit {
+                state: self.state.clone(),
+            }));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        state.waiters.push_back(tx);
+        Box::new(rx.map_err(|_| Error::Unreachable))
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let waiter = {
+            let mut state = self.state.lock().expect("lock to acquire");
+            let waiter = state.waiters.pop_front();
+            if waiter.is_none() {
+                state.available += 1;
+            }
+            waiter
+        };
+
+        // Slot is handed over to the waiter. If it is gone - the returned
+        // permit is dropped and passes the slot further.
+        if let Some(waiter) = waiter {
+            let _ = waiter.send(Permit {
+                state: self.state.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_limit_concurrency() {
+        let limiter = Limiter::new(1);
+
+        // Polling requires a task context
+        future::lazy(|| {
+            let first = limiter.acquire().wait().expect("permit to acquire");
+            let mut second = limiter.acquire();
+            let third = limiter.acquire();
+            assert!(second.poll().expect("poll to succeed").is_not_ready());
+
+            drop(third);
+            drop(first);
+            let second = match second.poll().expect("poll to succeed") {
+                Async::Ready(permit) => permit,
+                Async::NotReady => panic!("expected permit to be ready"),
+            };
+
+            // Abandoned waiter passes the slot further
+            drop(second);
+            future::ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        assert_eq!(limiter.state.lock().unwrap().available, 1);
+    }
+}
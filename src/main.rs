@@ -12,6 +12,7 @@ use futures::prelude::*;
 use naught::config::{Config, UserConfig};
 use naught::server::Server;
 
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::prelude::*;
 
@@ -43,6 +44,11 @@ fn main() {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("drain")
+                .long("drain")
+                .help("Hands off containers of the server at host and port, and stops it"),
+        )
         .get_matches();
 
     let config = if let Some(config) = matches.value_of("config") {
@@ -56,6 +62,7 @@ fn main() {
     } else {
         panic!("config file not present");
     };
+    let config = Config::try_from(config).expect("config to be valid");
 
     let port: u16 = matches
         .value_of("port")
@@ -64,10 +71,25 @@ fn main() {
         .expect("Invalid port value");
     let host = matches.value_of("host").unwrap();
 
-    let server = Server::new(Config::from(config));
+    if matches.is_present("drain") {
+        let uri = format!("http://{}:{}", host, port);
+        let drain = Server::drain(&config, &uri).for_each(|(remaining, total)| {
+            println!("{} of {} containers left to hand off", remaining, total);
+            Ok(())
+        });
+
+        tokio::run(drain.map_err(|err| {
+            error!("Got error {:#?}", err);
+            panic!("Drain failed");
+        }));
+        return;
+    }
+
+    let mut server = Server::new(config);
     let listen = server.listen(port, host);
 
-    tokio::run(listen.map_err(|err| {
+    // Open connections should not keep drained server running
+    tokio::run(listen.map(|_| std::process::exit(0)).map_err(|err| {
         error!("Got error {:#?}", err);
         panic!("Done");
     }));
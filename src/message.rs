@@ -8,19 +8,74 @@ pub mod common {
     #[derive(Serialize, Deserialize, Clone, Debug)]
     pub struct Ping {
         pub sender: String,
+
+        // Full list of active peers, sent only when the recipient has to
+        // sync with the sender
+        #[serde(default)]
         pub peers: Vec<String>,
+
+        // Peers added and removed since the version acknowledged by the
+        // recipient
+        #[serde(default)]
+        pub joined: Vec<String>,
+        #[serde(default)]
+        pub left: Vec<String>,
+
+        // Version of the sender's peer set, version of the recipient's peer
+        // set applied by the sender, and the digest of the sender's peer set
+        #[serde(default)]
+        pub version: u64,
+        #[serde(default)]
+        pub ack: u64,
+        #[serde(default)]
+        pub digest: u64,
+
+        // Incarnation of the sender, incremented to refute suspicion
+        #[serde(default)]
+        pub incarnation: u64,
+
+        // Peers suspected by the sender to have failed
+        #[serde(default)]
+        pub suspects: Vec<Suspect>,
+
+        // Sender is leaving the cluster and should not get new containers
+        #[serde(default)]
+        pub leaving: bool,
+
+        // Relative capacity of the sender
+        #[serde(default = "default_weight")]
+        pub weight: f64,
+    }
+
+    fn default_weight() -> f64 {
+        1.0
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct Suspect {
+        pub uri: String,
+        pub incarnation: u64,
     }
 }
 
 pub mod request {
     use super::*;
+    use serde::{Deserialize, Serialize};
 
     pub use common::Ping;
+
+    // Ask peer to ping `target` on behalf of the sender
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct PingReq {
+        pub target: String,
+        pub ping: Ping,
+    }
 }
 
 pub mod response {
     use super::*;
     use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
 
     pub use common::Ping;
 
@@ -31,6 +86,22 @@ pub mod response {
 
         pub uri: String,
         pub peers: Vec<String>,
+
+        // Request statistics by peer uri
+        #[serde(default)]
+        pub health: HashMap<String, Health>,
+
+        // Failure detector suspicion level by peer uri
+        #[serde(default)]
+        pub phi: HashMap<String, f64>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Health {
+        pub success_rate: f64,
+        pub latency_ms: f64,
+        pub failures: u32,
+        pub circuit_open: bool,
     }
 
     #[derive(Serialize, Debug)]
@@ -38,6 +109,14 @@ pub mod response {
         pub error: crate::error::Error,
     }
 
+    // Progress of handing off containers before leaving the cluster
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Drain {
+        pub containers: usize,
+        pub remaining: usize,
+        pub done: bool,
+    }
+
     #[derive(Serialize, Debug)]
     pub struct Store {
         pub container: String,
@@ -46,7 +125,9 @@ pub mod response {
 
     pub struct Fetch {
         pub peer: String,
+        pub status: hyper::StatusCode,
         pub mime: String,
+        pub headers: hyper::HeaderMap,
         pub body: hyper::Body,
     }
 }
@@ -2,39 +2,89 @@ extern crate futures;
 extern crate hyper;
 extern crate rand;
 extern crate serde_json;
+extern crate siphasher;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hasher;
 use std::iter::FromIterator;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 use futures::future;
 use futures::prelude::*;
+use futures::stream;
+use hyper::header::HeaderValue;
+use hyper::StatusCode;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
+use siphasher::sip::SipHasher;
 
-use crate::client::Client;
+use crate::client::{Client, NOT_FOUND_PAGE};
 use crate::config::Config;
 use crate::data::Data;
 use crate::error::Error;
-use crate::message::{common, response};
+use crate::hedge::Hedged;
+use crate::message::{common, request, response};
 use crate::peer::Peer;
+use crate::redirects::Redirect;
 use crate::resource::Resource;
+use crate::serve::{serve_file, serve_redirect};
+use crate::store::ContainerStore;
 
 type MaybePing = Option<common::Ping>;
-type FuturePingVec = Box<Future<Item = Vec<MaybePing>, Error = Error> + Send>;
+type FutureProbe = Box<Future<Item = Probe, Error = Error> + Send>;
 type FuturePing = Box<Future<Item = MaybePing, Error = Error> + Send>;
 type FutureFetch = Box<Future<Item = response::Fetch, Error = Error> + Send>;
 type FutureURI = Box<Future<Item = Option<String>, Error = Error> + Send>;
 type FutureKeyVec = Box<Future<Item = Vec<String>, Error = Error> + Send>;
 type FutureMaybeKey = Box<Future<Item = Option<String>, Error = Error> + Send>;
 type FutureBool = Box<Future<Item = bool, Error = Error> + Send>;
+type FutureEviction = Box<Future<Item = (String, u64, bool), Error = Error> + Send>;
+type FutureEmpty = Box<Future<Item = (), Error = Error> + Send>;
+
+// Containers that failed to reach some of their peers
+type RepairQueue = Arc<Mutex<HashSet<String>>>;
+
+// Containers confirmed to be stored by their new owners while draining
+type HandedOff = Arc<Mutex<HashSet<String>>>;
+
+struct DrainState {
+    started_at: Instant,
+    handed_off: HandedOff,
+}
+
+// Number of recent peer additions and removals kept for sending deltas.
+// Peers that are further behind get the full list.
+const MEMBERS_LOG_SIZE: usize = 1024;
+
+enum MemberChange {
+    Joined(String),
+    Left(String),
+}
+
+// Outcome of pinging a peer, either directly or through other peers
+pub enum Probe {
+    Alive(common::Ping),
+    Failed(String),
+}
 
 pub struct Node {
     config: Config,
     uri: String,
     peers: HashMap<String, Peer>,
-    data: HashMap<String, Data>,
+    data: Box<ContainerStore>,
+
+    // Peers with a probe in flight, not pinged again until it completes
+    probing: HashSet<String>,
+
+    // Incremented to refute suspicion of other peers
+    incarnation: u64,
+
+    // Version of the peer set, incremented on every added or removed peer,
+    // and the recent changes
+    members_version: u64,
+    members_log: VecDeque<(u64, MemberChange)>,
 
     // Last peers before rebalance
     last_peers: HashMap<String, Peer>,
@@ -43,6 +93,11 @@ pub struct Node {
 
     // Shared client with connection pool
     client: Client,
+
+    repair_queue: RepairQueue,
+
+    // Present once this node is leaving the cluster
+    drain_state: Option<DrainState>,
 }
 
 impl Node {
@@ -54,7 +109,7 @@ impl Node {
         }
     }
 
-    pub fn new(bind_addr: SocketAddr, config: Config) -> Node {
+    pub fn new(bind_addr: SocketAddr, config: Config, data: Box<ContainerStore>) -> Node {
         let uri = Node::public_uri(
             bind_addr.ip(),
             config.https_port.unwrap_or_else(|| bind_addr.port()),
@@ -66,12 +121,23 @@ impl Node {
             config,
             uri,
             peers: HashMap::new(),
-            data: HashMap::new(),
+            data,
+
+            probing: HashSet::new(),
+
+            incarnation: 0,
+
+            members_version: 0,
+            members_log: VecDeque::new(),
 
             last_peers: HashMap::new(),
             last_peer_uris: HashSet::new(),
 
             client,
+
+            repair_queue: Arc::new(Mutex::new(HashSet::new())),
+
+            drain_state: None,
         }
     }
 
@@ -87,23 +153,47 @@ impl Node {
 
     // NOTE: This is not a part of p2p protocol, mostly needed for debugging
     pub fn recv_info(&self) -> Result<response::Info, Error> {
+        let now = Instant::now();
         Ok(response::Info {
             hash_seed: vec![self.config.hash_seed.0, self.config.hash_seed.1],
             replicate: self.config.replicate,
 
             uri: self.uri.clone(),
             peers: self.get_peer_uris(),
+
+            health: self.client.health().scores(),
+            phi: self
+                .peers
+                .iter()
+                .filter_map(|(uri, peer)| peer.phi(now).map(|phi| (uri.clone(), phi)))
+                .collect(),
         })
     }
 
     pub fn recv_ping(&mut self, msg: &common::Ping) -> Result<common::Ping, Error> {
-        self.on_ping(&msg.sender, &msg.peers);
+        self.on_ping(msg);
 
-        Ok(self.construct_ping())
+        Ok(self.construct_ping(&msg.sender))
+    }
+
+    // Ping the target on behalf of the sender and pass back the reply
+    pub fn recv_ping_req(&self, msg: &request::PingReq) -> FuturePing {
+        if !self.peers.contains_key(&msg.target) {
+            return Box::new(future::err(Error::PingFailed));
+        }
+
+        let ping = match serde_json::to_string(&msg.ping) {
+            Ok(json) => json,
+            Err(err) => {
+                return Box::new(future::err(Error::from(err)));
+            }
+        };
+
+        self.client.ping(&msg.target, &ping)
     }
 
     pub fn peek(&self, container: &str) -> Result<(), Error> {
-        if self.data.contains_key(container) {
+        if self.data.contains(container) {
             trace!("peek existing container: {}", container);
             Ok(())
         } else {
@@ -112,26 +202,123 @@ impl Node {
         }
     }
 
-    pub fn fetch(&self, container: &str, uri: &str, redirect: bool) -> FutureFetch {
-        if let Some(entry) = self.data.get(container) {
+    fn serve_local(
+        peer: &str,
+        entry: &Data,
+        uri: &str,
+        query: Option<&str>,
+        headers: &hyper::HeaderMap,
+    ) -> Result<response::Fetch, Error> {
+        if let Some(file) = entry.serve(uri) {
+            return serve_file(peer, file, headers);
+        }
+
+        if entry.is_directory(uri) {
+            // Relative links in the index must resolve within the directory
+            if !uri.is_empty() && !uri.ends_with('/') {
+                let location = match query {
+                    Some(query) => format!("/{}/?{}", uri, query),
+                    None => format!("/{}/", uri),
+                };
+                return serve_redirect(peer, StatusCode::MOVED_PERMANENTLY, &location);
+            }
+
+            if let Some(listing) = entry.listing(uri) {
+                return serve_file(peer, &listing, headers);
+            }
+        }
+
+        match entry.fallback(uri) {
+            Some((StatusCode::OK, file)) => serve_file(peer, file, headers),
+            Some((status, file)) => {
+                // Conditional and range requests do not apply to error pages
+                let mut request = hyper::HeaderMap::new();
+                if let Some(value) = headers.get(hyper::header::ACCEPT_ENCODING) {
+                    request.insert(hyper::header::ACCEPT_ENCODING, value.clone());
+                }
+
+                let mut response = serve_file(peer, file, &request)?;
+                response.status = status;
+                Ok(response)
+            }
+            None => {
+                trace!("fetch missing uri: {}", uri);
+                Err(Error::NotFound)
+            }
+        }
+    }
+
+    fn serve_entry(
+        peer: &str,
+        entry: &Data,
+        uri: &str,
+        query: Option<&str>,
+        redirect: bool,
+        headers: &hyper::HeaderMap,
+        cache_control: &HeaderValue,
+    ) -> Result<response::Fetch, Error> {
+        let path = format!("/{}", uri);
+        let response = match entry.redirect_rules().resolve(&path) {
+            Some(Redirect::Redirect(status, location)) => {
+                trace!("fetch redirect uri: {} to: {}", uri, location);
+                serve_redirect(peer, status, &location)
+            }
+            Some(Redirect::Rewrite(target)) => {
+                trace!("fetch rewrite uri: {} to: {}", uri, target);
+                Node::serve_local(peer, entry, &target[1..], query, headers)
+            }
+            None => Node::serve_local(peer, entry, uri, query, headers),
+        };
+
+        response.map(|mut response| {
+            response
+                .headers
+                .insert(hyper::header::CACHE_CONTROL, cache_control.clone());
+
+            // Rules match the requested path, not the rewritten one
+            entry.header_rules().apply(&path, &mut response.headers);
+
+            // Let the proxying peer tell the page from a missing container
+            if !redirect && response.status == StatusCode::NOT_FOUND {
+                response
+                    .headers
+                    .insert(NOT_FOUND_PAGE, HeaderValue::from_static("true"));
+            }
+            response
+        })
+    }
+
+    pub fn fetch(
+        &self,
+        container: &str,
+        uri: &str,
+        query: Option<&str>,
+        redirect: bool,
+        headers: &hyper::HeaderMap,
+    ) -> FutureFetch {
+        if self.data.contains(container) {
             trace!(
                 "fetch existing container: {} redirect: {}",
                 container,
                 redirect
             );
 
-            let file = match entry.serve(uri) {
-                Some(file) => file,
-                None => {
-                    trace!("fetch missing uri: {} in container: {}", uri, container,);
-                    return Box::new(future::err(Error::NotFound));
-                }
-            };
-
-            return Box::new(future::ok(response::Fetch {
-                peer: self.uri.to_string(),
-                mime: file.mime.clone(),
-                body: hyper::Body::from(file.content.clone()),
+            // Loading the container should not block the node
+            let peer = self.uri.clone();
+            let cache_control = self.config.cache_control.clone();
+            let uri = uri.to_string();
+            let query = query.map(str::to_string);
+            let headers = headers.clone();
+            return Box::new(self.data.load(container).and_then(move |entry| {
+                Node::serve_entry(
+                    &peer,
+                    &entry,
+                    &uri,
+                    query.as_deref(),
+                    redirect,
+                    &headers,
+                    &cache_control,
+                )
             }));
         }
 
@@ -147,27 +334,34 @@ impl Node {
             return Box::new(future::err(Error::NotFound));
         }
 
-        // Shuffle resources to balance requests fairly
+        // Shuffle resources to balance requests fairly, but prefer healthy
+        // ones
         let mut rng = thread_rng();
         resources.shuffle(&mut rng);
+        self.order_by_health(&mut resources);
 
-        let response: FutureFetch = resources
+        // Peers need the query too, e.g. for their redirects
+        let uri = match query {
+            Some(query) => format!("{}?{}", uri, query),
+            None => uri.to_string(),
+        };
+        let fetches: Vec<FutureFetch> = resources
             .into_iter()
-            .map(|resource| resource.fetch(&self.client, uri))
-            .fold(Box::new(future::err(Error::Unreachable)), |acc, f| {
-                Box::new(acc.or_else(move |_| f))
-            });
+            .map(|resource| resource.fetch(&self.client, &uri, headers))
+            .collect();
 
-        Box::new(response)
+        Box::new(Hedged::new(fetches, self.config.hedge_delay))
     }
 
+    // `entry` is parsed by the caller, so that it does not happen while the
+    // node is locked
     pub fn store(
         &mut self,
         container: &str,
-        value: Vec<u8>,
+        entry: Arc<Data>,
         redirect: bool,
     ) -> Box<Future<Item = response::Store, Error = Error> + Send> {
-        if self.data.contains_key(container) {
+        if self.data.contains(container) {
             trace!("duplicate container: {}", container);
             return Box::new(future::ok(response::Store {
                 container: container.to_string(),
@@ -175,13 +369,6 @@ impl Node {
             }));
         }
 
-        let entry = match Data::from_tar(value) {
-            Ok(entry) => entry,
-            Err(err) => {
-                return Box::new(future::err(err));
-            }
-        };
-
         // Store only locally when redirect is `false`
         let resources: Vec<Resource> = self
             .find_resources(container)
@@ -195,6 +382,14 @@ impl Node {
             return Box::new(future::err(Error::NonLocalStore(container.to_string())));
         }
 
+        // Containers owned by this node are always accepted, the rest only
+        // while within the budget
+        let is_owned = resources.iter().any(Resource::is_local);
+        if !is_owned && self.is_over_budget(entry.size()) {
+            trace!("over budget for container: {}", container);
+            return Box::new(future::err(Error::OverBudget(container.to_string())));
+        }
+
         trace!("new container: {}", container);
 
         let remote: Vec<FutureURI> = resources
@@ -202,13 +397,19 @@ impl Node {
             .map(|resource| -> FutureURI {
                 // TODO(indutny): excessive cloning?
                 let target_uri = resource.peer_uri().to_string();
+                let repair_queue = self.repair_queue.clone();
+                let container = container.to_string();
 
                 let store = resource
                     .store(&self.client, &entry)
                     .map(move |_| Some(target_uri))
-                    .or_else(|err| {
+                    .or_else(move |err| {
                         // Single failed store should not fail others
                         trace!("remote store failed due to error: {:?}", err);
+                        repair_queue
+                            .lock()
+                            .expect("lock to acquire")
+                            .insert(container);
                         future::ok(None)
                     });
                 Box::new(store)
@@ -225,12 +426,16 @@ impl Node {
             })
         });
 
-        self.data.insert(container.to_string(), entry);
+        if let Err(err) = self.data.put(container, entry) {
+            return Box::new(future::err(err));
+        }
 
         Box::new(uris)
     }
 
-    pub fn send_pings(&mut self) -> FuturePingVec {
+    // Probes of the peers that are due to be pinged, results are passed
+    // back to `recv_probe`
+    pub fn send_pings(&mut self) -> Vec<(String, FutureProbe)> {
         let now = Instant::now();
 
         // Remove stale peers
@@ -246,38 +451,245 @@ impl Node {
             })
             .collect();
 
-        for key in to_remove.iter() {
-            trace!("remove peer: {}", key);
-            self.peers.remove(key);
+        for key in to_remove {
+            self.remove_peer(key);
         }
 
         // Ping alive peers
+        let probing = &self.probing;
         let uris: Vec<String> = self
             .peers
-            .values_mut()
-            .filter(|peer| peer.should_ping(now))
+            .values()
+            .filter(|peer| peer.should_ping(now) && !probing.contains(peer.uri()))
             .map(|peer| peer.uri().to_string())
             .collect();
 
-        let ping = match serde_json::to_string(&self.construct_ping()) {
-            Ok(json) => json,
+        let active = self.get_peer_uris();
+        let mut probes: Vec<(String, FutureProbe)> = vec![];
+        for peer_uri in uris {
+            self.probing.insert(peer_uri.clone());
+
+            let ping = self.construct_ping(&peer_uri);
+            let json_ping = match serde_json::to_string(&ping) {
+                Ok(json) => json,
+                Err(err) => {
+                    probes.push((peer_uri, Box::new(future::err(Error::from(err)))));
+                    continue;
+                }
+            };
+
+            // Prepared in advance, used only if direct ping fails
+            let ping_req = request::PingReq {
+                target: peer_uri.clone(),
+                ping: ping.clone(),
+            };
+            let json_ping_req = match serde_json::to_string(&ping_req) {
+                Ok(json) => json,
+                Err(err) => {
+                    probes.push((peer_uri, Box::new(future::err(Error::from(err)))));
+                    continue;
+                }
+            };
+            let helpers: Vec<String> = active
+                .iter()
+                .filter(|uri| **uri != peer_uri)
+                .cloned()
+                .collect::<Vec<String>>()
+                .choose_multiple(&mut thread_rng(), self.config.indirect_probes)
+                .cloned()
+                .collect();
+
+            let client = self.client.clone();
+            let target = peer_uri.clone();
+            let f = self
+                .client
+                .ping(&peer_uri, &json_ping)
+                .then(move |result| -> FutureProbe {
+                    match result {
+                        Ok(Some(reply)) => return Box::new(future::ok(Probe::Alive(reply))),
+                        Ok(None) => {}
+                        Err(err) => {
+                            trace!("ping to {} failed due to error: {:?}", target, err);
+                        }
+                    }
+
+                    Node::ping_indirectly(&client, target, &json_ping_req, helpers)
+                });
+            probes.push((peer_uri, Box::new(f)));
+        }
+        probes
+    }
+
+    pub fn recv_probe(&mut self, uri: &str, probe: Result<Probe, Error>) {
+        self.probing.remove(uri);
+
+        match probe {
+            Ok(Probe::Alive(ping)) => {
+                // Ignore errors
+                self.recv_ping(&ping).map(|_| ()).unwrap_or(());
+            }
+            Ok(Probe::Failed(uri)) => self.suspect_peer(&uri),
             Err(err) => {
-                return Box::new(future::err(Error::from(err)));
+                trace!("probe of {} failed due to error: {:?}", uri, err);
+            }
+        }
+    }
+
+    // Announce departure in the pings and start handing off containers.
+    // Returns the progress, calling it again is safe.
+    pub fn start_drain(&mut self) -> response::Drain {
+        if self.drain_state.is_none() {
+            trace!("start draining");
+            self.drain_state = Some(DrainState {
+                started_at: Instant::now(),
+                handed_off: Arc::new(Mutex::new(HashSet::new())),
+            });
+        }
+
+        self.drain_progress()
+    }
+
+    pub fn drain_progress(&self) -> response::Drain {
+        let containers = self.data.list();
+        let remaining = match self.drain_state {
+            Some(ref state) => {
+                let handed_off = state.handed_off.lock().expect("lock to acquire");
+                containers
+                    .iter()
+                    .filter(|container| !handed_off.contains(*container))
+                    .count()
             }
+            None => containers.len(),
         };
 
-        let pings: Vec<FuturePing> = uris
+        response::Drain {
+            containers: containers.len(),
+            remaining,
+            done: self.is_drained(),
+        }
+    }
+
+    // Every container is stored elsewhere, and all peers had a chance to
+    // learn about the departure
+    pub fn is_drained(&self) -> bool {
+        let state = match self.drain_state {
+            Some(ref state) => state,
+            None => return false,
+        };
+
+        if state.started_at + self.config.ping_every.max > Instant::now() {
+            return false;
+        }
+
+        let handed_off = state.handed_off.lock().expect("lock to acquire");
+        self.data
+            .list()
+            .iter()
+            .all(|container| handed_off.contains(container))
+    }
+
+    // Send containers to the peers that own them once this node is gone.
+    // Container is handed off when every new owner confirms having it.
+    pub fn drain(&self) -> FutureEmpty {
+        let handed_off = match self.drain_state {
+            Some(ref state) => state.handed_off.clone(),
+            None => return Box::new(future::ok(())),
+        };
+
+        let now = Instant::now();
+        let peer_uris: Vec<String> = self
+            .peers
+            .values()
+            .filter(|peer| peer.is_stable(now) && peer.is_active(now))
+            .map(|peer| peer.uri().to_string())
+            .collect();
+        if peer_uris.is_empty() {
+            trace!("drain: no peers to hand off containers to");
+            return Box::new(future::ok(()));
+        }
+
+        let union: Vec<&String> = peer_uris.iter().collect();
+        let removed_peers = HashSet::from_iter(vec![self.uri.clone()]);
+
+        let pending: Vec<String> = {
+            let handed_off = handed_off.lock().expect("lock to acquire");
+            self.data
+                .list()
+                .into_iter()
+                .filter(|container| !handed_off.contains(container))
+                .collect()
+        };
+
+        let handoffs: Vec<FutureEmpty> = pending
             .into_iter()
-            .map(|peer_uri| -> FuturePing {
-                Box::new(self.client.ping(&peer_uri, &ping).or_else(move |err| {
-                    // Single failed ping should not prevent other pings
-                    // from happening
-                    trace!("ping to {} failed due to error: {:?}", peer_uri, err);
-                    future::ok(None)
+            .map(|container| -> FutureEmpty {
+                let targets = self.find_rebalance_resources(
+                    &container,
+                    &union,
+                    &HashSet::new(),
+                    &removed_peers,
+                );
+
+                let mut owners: Vec<Resource> = union
+                    .iter()
+                    .map(|peer_uri| self.construct_peer_resource(&self.peers, peer_uri, &container))
+                    .collect();
+                owners.sort();
+                owners.truncate(self.config.replicate as usize + 1);
+
+                let client = self.client.clone();
+                let handed_off = handed_off.clone();
+                let f = self.data.load(&container).and_then(move |entry| {
+                    // New owners get the container, existing ones are checked
+                    // and get it too if missing
+                    let checks: Vec<FutureBool> = owners
+                        .into_iter()
+                        .map(|owner| -> FutureBool {
+                            let f = if targets.contains(&owner) {
+                                owner.store(&client, &entry)
+                            } else {
+                                let client = client.clone();
+                                let entry = entry.clone();
+                                Box::new(
+                                    owner
+                                        .peek(&client)
+                                        .or_else(move |_| owner.store(&client, &entry)),
+                                )
+                            };
+                            Box::new(f.map(|_| true).or_else(|err| {
+                                trace!("drain: handoff failed due to error: {:?}", err);
+                                future::ok(false)
+                            }))
+                        })
+                        .collect();
+
+                    future::join_all(checks).map(move |successes| {
+                        if successes.into_iter().all(|v| v) {
+                            trace!("drain: handed off container: {}", container);
+                            handed_off
+                                .lock()
+                                .expect("lock to acquire")
+                                .insert(container);
+                        }
+                    })
+                });
+
+                // Removed or evicted since
+                Box::new(f.or_else(|err| {
+                    trace!("drain: failed to load container due to error: {:?}", err);
+                    future::ok(())
                 }))
             })
             .collect();
-        Box::new(future::join_all(pings))
+
+        Box::new(self.bounded(handoffs).for_each(|_| Ok(())))
+    }
+
+    pub fn suspect_peer(&mut self, uri: &str) {
+        if let Some(peer) = self.peers.get_mut(uri) {
+            let incarnation = peer.incarnation();
+            peer.suspect(incarnation, Instant::now());
+        }
     }
 
     pub fn rebalance(&mut self) -> FutureKeyVec {
@@ -294,7 +706,15 @@ impl Node {
         let added_peers = HashSet::from_iter(new_peers.difference(&self.last_peer_uris).cloned());
         let removed_peers = HashSet::from_iter(self.last_peer_uris.difference(&new_peers).cloned());
 
-        if added_peers.is_empty() && removed_peers.is_empty() {
+        let reweighted =
+            new_peers.iter().any(
+                |uri| match (self.peers.get(uri), self.last_peers.get(uri)) {
+                    (Some(peer), Some(last_peer)) => peer.weight() != last_peer.weight(),
+                    _ => false,
+                },
+            );
+
+        if added_peers.is_empty() && removed_peers.is_empty() && !reweighted {
             // No rebalancing needed
             return Box::new(future::ok(vec![]));
         }
@@ -308,34 +728,58 @@ impl Node {
 
         let obsolete_keys: Vec<FutureMaybeKey> = self
             .data
-            .iter()
-            .map(|(container, entry)| -> FutureMaybeKey {
+            .list()
+            .into_iter()
+            .map(|container| -> FutureMaybeKey {
                 let resources =
-                    self.find_rebalance_resources(container, &union, &added_peers, &removed_peers);
+                    self.find_rebalance_resources(&container, &union, &added_peers, &removed_peers);
 
-                let keep_local = resources.iter().any(Resource::is_local);
+                // Nothing to send, no need to load the container
+                if resources.is_empty() {
+                    return Box::new(future::ok(None));
+                }
 
-                let successes: Vec<FutureBool> = resources
-                    .into_iter()
-                    .map(|resource| -> FutureBool {
-                        Box::new(resource.store(&self.client, &entry).map(|_| true).or_else(
-                            |err| {
-                                // Single failed rebalance should not fail others
-                                trace!("remote rebalance failed due to error: {:?}", err);
-                                future::ok(false)
-                            },
-                        ))
+                let keep_local = resources.iter().any(Resource::is_local);
+                let client = self.client.clone();
+                let repair_queue = self.repair_queue.clone();
+
+                let f = self.data.load(&container).and_then(move |entry| {
+                    let successes: Vec<FutureBool> =
+                        resources
+                            .into_iter()
+                            .map(|resource| -> FutureBool {
+                                Box::new(resource.store(&client, &entry).map(|_| true).or_else(
+                                    |err| {
+                                        // Single failed rebalance should not fail others
+                                        trace!("remote rebalance failed due to error: {:?}", err);
+                                        future::ok(false)
+                                    },
+                                ))
+                            })
+                            .collect();
+
+                    future::join_all(successes).map(move |successes| {
+                        if successes.iter().any(|v| !v) {
+                            repair_queue
+                                .lock()
+                                .expect("lock to acquire")
+                                .insert(container.clone());
+                        }
+
+                        if !keep_local && successes.into_iter().any(|v| v) {
+                            Some(container)
+                        } else {
+                            None
+                        }
                     })
-                    .collect();
+                });
 
-                let container = container.clone();
-
-                Box::new(future::join_all(successes).and_then(move |successes| {
-                    if !keep_local && successes.into_iter().any(|v| v) {
-                        future::ok(Some(container))
-                    } else {
-                        future::ok(None)
-                    }
+                Box::new(f.or_else(|err| {
+                    trace!(
+                        "rebalance: failed to load container due to error: {:?}",
+                        err
+                    );
+                    future::ok(None)
                 }))
             })
             .collect();
@@ -344,15 +788,163 @@ impl Node {
         self.last_peers = self.peers.clone();
 
         Box::new(
-            future::join_all(obsolete_keys)
-                .map(|keys| keys.into_iter().filter_map(|container| container).collect()),
+            self.bounded(obsolete_keys)
+                .filter_map(|container| container)
+                .collect(),
         )
     }
 
+    // Send queued containers to their peers again. Containers that are still
+    // not stored on every peer are queued for the next attempt.
+    pub fn repair(&self) -> FutureEmpty {
+        let containers: Vec<String> = self
+            .repair_queue
+            .lock()
+            .expect("lock to acquire")
+            .drain()
+            .collect();
+
+        let repairs: Vec<FutureEmpty> = containers
+            .into_iter()
+            .filter_map(|container| -> Option<FutureEmpty> {
+                // Removed or evicted since
+                if !self.data.contains(&container) {
+                    return None;
+                }
+
+                let resources: Vec<Resource> = self
+                    .find_resources(&container)
+                    .into_iter()
+                    .filter(|resource| !resource.is_local())
+                    .collect();
+
+                let client = self.client.clone();
+                let repair_queue = self.repair_queue.clone();
+                let f = self.data.load(&container).then(move |entry| {
+                    let stores: Vec<FutureBool> = match entry {
+                        Ok(entry) => resources
+                            .into_iter()
+                            .map(|resource| -> FutureBool {
+                                Box::new(resource.store(&client, &entry).map(|_| true).or_else(
+                                    |err| {
+                                        trace!("repair failed due to error: {:?}", err);
+                                        future::ok(false)
+                                    },
+                                ))
+                            })
+                            .collect(),
+                        Err(err) => {
+                            trace!("repair failed to load container due to error: {:?}", err);
+                            vec![Box::new(future::ok(false))]
+                        }
+                    };
+
+                    future::join_all(stores).map(move |successes| {
+                        if successes.into_iter().all(|v| v) {
+                            trace!("repaired container: {}", container);
+                        } else {
+                            repair_queue
+                                .lock()
+                                .expect("lock to acquire")
+                                .insert(container);
+                        }
+                    })
+                });
+                Some(Box::new(f))
+            })
+            .collect();
+
+        Box::new(self.bounded(repairs).for_each(|_| Ok(())))
+    }
+
+    // Find containers that can be dropped to get back within the memory
+    // budget. Only containers that have at least `replicate` confirmed copies
+    // on other peers are evicted.
+    pub fn evict(&self) -> FutureKeyVec {
+        let used = self.data.used_bytes();
+        let budget = match self.config.memory_budget {
+            Some(budget) if used > budget => budget,
+            _ => {
+                return Box::new(future::ok(vec![]));
+            }
+        };
+
+        trace!("evict: start used={} budget={}", used, budget);
+
+        // Only replicas of containers owned by other peers are evicted.
+        // Sizes come from the store, so that blobs are not loaded.
+        let mut candidates: Vec<(u64, String, Vec<Resource>)> = self
+            .data
+            .list()
+            .into_iter()
+            .filter_map(|container| {
+                let size = self.data.size(&container)?;
+                let (local, remote): (Vec<Resource>, Vec<Resource>) = self
+                    .find_resources(&container)
+                    .into_iter()
+                    .partition(Resource::is_local);
+                if local.is_empty() {
+                    Some((size, container, remote))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        // Largest first
+        candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.0));
+
+        let min_copies = std::cmp::max(self.config.replicate as usize, 1);
+
+        let confirmed: Vec<FutureEviction> = candidates
+            .into_iter()
+            .map(|(size, container, remote)| -> FutureEviction {
+                let peeks: Vec<FutureBool> = remote
+                    .into_iter()
+                    .map(|resource| -> FutureBool {
+                        Box::new(
+                            resource
+                                .peek(&self.client)
+                                .map(|_| true)
+                                .or_else(|_| future::ok(false)),
+                        )
+                    })
+                    .collect();
+
+                Box::new(future::join_all(peeks).map(move |peeks| {
+                    let copies = peeks.into_iter().filter(|v| *v).count();
+                    (container, size, copies >= min_copies)
+                }))
+            })
+            .collect();
+
+        Box::new(future::join_all(confirmed).map(move |candidates| {
+            let mut used = used;
+            let mut keys = vec![];
+            for (container, size, confirmed) in candidates {
+                if used <= budget {
+                    break;
+                }
+                if !confirmed {
+                    trace!("evict: not enough copies of container: {}", container);
+                    continue;
+                }
+
+                used -= size;
+                keys.push(container);
+            }
+
+            trace!("evict: done keys={:?} used={}", keys, used);
+            keys
+        }))
+    }
+
     pub fn remove(&mut self, keys: Vec<String>) {
         for key in keys {
             trace!("remove key: {}", key);
-            self.data.remove(&key);
+            if let Err(err) = self.data.remove(&key) {
+                error!("failed to remove key: {} due to: {:?}", key, err);
+            }
         }
     }
 
@@ -364,37 +956,266 @@ impl Node {
         let uri_str = uri.to_string();
         if !self.peers.contains_key(&uri_str) {
             trace!("new peer: {}", uri);
-            self.peers
-                .insert(uri_str.clone(), Peer::new(uri_str, self.config.clone()));
+            self.peers.insert(
+                uri_str.clone(),
+                Peer::new(uri_str.clone(), self.config.clone()),
+            );
+
+            self.log_member_change(MemberChange::Joined(uri_str));
         }
     }
 
+    fn remove_peer(&mut self, uri: String) {
+        if self.peers.remove(&uri).is_some() {
+            trace!("remove peer: {}", uri);
+            self.client.health().remove(&uri);
+            self.log_member_change(MemberChange::Left(uri));
+        }
+    }
+
+    fn log_member_change(&mut self, change: MemberChange) {
+        self.members_version += 1;
+        if self.members_log.len() == MEMBERS_LOG_SIZE {
+            self.members_log.pop_front();
+        }
+        self.members_log.push_back((self.members_version, change));
+    }
+
     // Internal methods
 
-    fn construct_ping(&self) -> common::Ping {
+    // Run at most `max_replications` of `fs` at once. Containers are loaded
+    // lazily, so that only as many are held in memory for sending.
+    fn bounded<T: Send + 'static>(
+        &self,
+        fs: Vec<Box<Future<Item = T, Error = Error> + Send>>,
+    ) -> Box<Stream<Item = T, Error = Error> + Send> {
+        Box::new(stream::iter_ok(fs).buffer_unordered(self.config.max_replications))
+    }
+
+    fn construct_ping(&self, recipient: &str) -> common::Ping {
+        let now = Instant::now();
+        let (ack, acked) = self
+            .peers
+            .get(recipient)
+            .map(|peer| (peer.seen_version(), peer.acked_version()))
+            .unwrap_or((0, 0));
+
+        // Only active peers are announced, so that failed ones are not
+        // brought back by others
+        let (peers, joined, left) = match self.changes_since(acked) {
+            Some((joined, left)) => (
+                vec![],
+                joined
+                    .into_iter()
+                    .filter(|uri| {
+                        uri != recipient
+                            && self
+                                .peers
+                                .get(uri)
+                                .map(|peer| peer.is_active(now))
+                                .unwrap_or(false)
+                    })
+                    .collect(),
+                left.into_iter().filter(|uri| uri != recipient).collect(),
+            ),
+            None => (self.get_peer_uris(), vec![], vec![]),
+        };
+
         common::Ping {
             sender: self.uri.clone(),
-            peers: self.get_peer_uris(),
+            peers,
+            joined,
+            left,
+            version: self.members_version,
+            ack,
+            digest: self.members_digest(),
+            incarnation: self.incarnation,
+            suspects: self
+                .peers
+                .values()
+                .filter(|peer| peer.is_suspected())
+                .map(|peer| common::Suspect {
+                    uri: peer.uri().to_string(),
+                    incarnation: peer.incarnation(),
+                })
+                .collect(),
+            leaving: self.drain_state.is_some(),
+            weight: self.config.weight,
+        }
+    }
+
+    // Peers added and removed after `version`, or `None` if the full list
+    // has to be sent
+    fn changes_since(&self, version: u64) -> Option<(Vec<String>, Vec<String>)> {
+        if version == 0 || version > self.members_version {
+            return None;
+        }
+
+        if let Some((oldest, _)) = self.members_log.front() {
+            if version + 1 < *oldest {
+                return None;
+            }
+        }
+
+        // Only the latest change of each peer matters
+        let mut joined: Vec<String> = vec![];
+        let mut left: Vec<String> = vec![];
+        for (_, change) in self.members_log.iter().filter(|(v, _)| *v > version) {
+            match change {
+                MemberChange::Joined(uri) => {
+                    left.retain(|other| other != uri);
+                    joined.push(uri.clone());
+                }
+                MemberChange::Left(uri) => {
+                    joined.retain(|other| other != uri);
+                    left.push(uri.clone());
+                }
+            }
+        }
+        Some((joined, left))
+    }
+
+    // Order-independent hash of the gossiped peer set: active peers and this
+    // node
+    fn members_digest(&self) -> u64 {
+        let mut uris = self.get_peer_uris();
+        uris.push(self.uri.clone());
+        uris.sort();
+
+        let mut hasher = SipHasher::new_with_keys(self.config.hash_seed.0, self.config.hash_seed.1);
+        for uri in uris {
+            hasher.write(uri.as_bytes());
+            hasher.write_u8(0);
+        }
+        hasher.finish()
+    }
+
+    // Ask other peers to ping the target, so that a fault of the network
+    // between us and the target alone does not mark it as failed
+    fn ping_indirectly(
+        client: &Client,
+        target: String,
+        json_ping_req: &str,
+        helpers: Vec<String>,
+    ) -> FutureProbe {
+        if helpers.is_empty() {
+            return Box::new(future::ok(Probe::Failed(target)));
+        }
+
+        let pings: Vec<FutureProbe> = helpers
+            .iter()
+            .map(|helper| -> FutureProbe {
+                Box::new(
+                    client
+                        .ping_req(helper, json_ping_req)
+                        .and_then(|reply| reply.map(Probe::Alive).ok_or(Error::PingFailed)),
+                )
+            })
+            .collect();
+
+        Box::new(future::select_ok(pings).then(move |result| match result {
+            Ok((probe, _)) => Ok(probe),
+            Err(err) => {
+                trace!("indirect ping to {} failed due to error: {:?}", target, err);
+                Ok(Probe::Failed(target))
+            }
+        }))
+    }
+
+    fn is_over_budget(&self, extra: u64) -> bool {
+        match self.config.memory_budget {
+            Some(budget) => self.data.used_bytes() + extra > budget,
+            None => false,
         }
     }
 
     fn construct_resource(&self, container: &str) -> Resource {
-        Resource::new(&self.uri, container, true, self.config.hash_seed)
+        Resource::new(
+            &self.uri,
+            container,
+            true,
+            self.config.hash_seed,
+            self.config.weight,
+        )
+    }
+
+    // Weight is looked up in `peers` first, falling back to the current
+    // peers
+    fn construct_peer_resource(
+        &self,
+        peers: &HashMap<String, Peer>,
+        peer_uri: &str,
+        container: &str,
+    ) -> Resource {
+        let weight = peers
+            .get(peer_uri)
+            .or_else(|| self.peers.get(peer_uri))
+            .map(Peer::weight)
+            .unwrap_or(1.0);
+        Resource::new(peer_uri, container, false, self.config.hash_seed, weight)
     }
 
-    fn on_ping(&mut self, sender: &str, peers: &[String]) {
-        if sender == self.uri {
+    fn on_ping(&mut self, msg: &common::Ping) {
+        let sender = &msg.sender;
+        if *sender == self.uri {
             return;
         }
 
         self.add_peer(&sender);
-        for peer_uri in peers {
+        for peer_uri in msg.peers.iter().chain(msg.joined.iter()) {
             self.add_peer(&peer_uri);
         }
 
+        // Peers that are still heard from stay
+        let now = Instant::now();
+        let left: Vec<String> = msg
+            .left
+            .iter()
+            .filter(|uri| {
+                self.peers
+                    .get(*uri)
+                    .map(|peer| !peer.is_active(now))
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+        for uri in left {
+            self.remove_peer(uri);
+        }
+
+        for suspect in &msg.suspects {
+            if suspect.uri == self.uri {
+                // Announce newer incarnation in the next pings
+                if suspect.incarnation >= self.incarnation {
+                    self.incarnation = suspect.incarnation + 1;
+                    trace!("refute suspicion with incarnation: {}", self.incarnation);
+                }
+            } else if let Some(peer) = self.peers.get_mut(&suspect.uri) {
+                peer.suspect(suspect.incarnation, now);
+            }
+        }
+
+        {
+            let sender_peer = self.peers.get_mut(sender).expect("Sender to be present");
+            sender_peer.observe_incarnation(msg.incarnation);
+            sender_peer.set_leaving(msg.leaving);
+            sender_peer.set_weight(msg.weight);
+            sender_peer.mark_alive();
+        }
+
+        // Sender is a part of its own peer set
+        let digest = self.members_digest();
         let sender_peer = self.peers.get_mut(sender).expect("Sender to be present");
 
-        sender_peer.mark_alive();
+        sender_peer.set_acked_version(msg.ack);
+        if msg.digest == digest {
+            sender_peer.set_seen_version(msg.version);
+        } else {
+            // Exchange full lists in the next pings
+            trace!("peer set mismatch with peer: {}", sender);
+            sender_peer.set_seen_version(0);
+            sender_peer.set_acked_version(0);
+        }
     }
 
     fn get_peer_uris(&self) -> Vec<String> {
@@ -419,15 +1240,36 @@ impl Node {
             .peers
             .values()
             .filter(|peer| peer.is_stable(now) && peer.is_active(now))
-            .map(|peer| Resource::new(peer.uri(), container, false, self.config.hash_seed))
+            .map(|peer| {
+                Resource::new(
+                    peer.uri(),
+                    container,
+                    false,
+                    self.config.hash_seed,
+                    peer.weight(),
+                )
+            })
             .collect();
         resources.push(self.construct_resource(container));
         resources.sort();
         resources.truncate(self.config.replicate as usize + 1);
+        self.order_by_health(&mut resources);
 
         resources
     }
 
+    // Stable sort, keeps the order of equally healthy resources
+    fn order_by_health(&self, resources: &mut [Resource]) {
+        let health = self.client.health();
+        resources.sort_by_key(|resource| {
+            if resource.is_local() {
+                0
+            } else {
+                health.rank(resource.peer_uri())
+            }
+        });
+    }
+
     fn find_rebalance_resources(
         &self,
         container: &str,
@@ -437,23 +1279,27 @@ impl Node {
     ) -> Vec<Resource> {
         let self_resource = self.construct_resource(container);
 
-        // TODO(indutny): LRU
-        let mut resources: Vec<Resource> = union
-            .iter()
-            .map(|peer_uri| Resource::new(peer_uri, container, false, self.config.hash_seed))
-            .collect();
-        resources.push(self_resource.clone());
-        resources.sort();
+        // Old placement uses weights from the last rebalance, so that
+        // changed weights move containers too
+        let construct = |peers: &HashMap<String, Peer>| -> Vec<Resource> {
+            // TODO(indutny): LRU
+            let mut resources: Vec<Resource> = union
+                .iter()
+                .map(|peer_uri| self.construct_peer_resource(peers, peer_uri, container))
+                .collect();
+            resources.push(self_resource.clone());
+            resources.sort();
+            resources
+        };
 
         // NOTE: Local peer should always appear in new resources, since we
         // are using it for detecting moved keys
-        let mut new_resources: Vec<Resource> = resources
-            .iter()
+        let mut new_resources: Vec<Resource> = construct(&self.peers)
+            .into_iter()
             .filter(|resource| !removed_peers.contains(resource.peer_uri()))
-            .cloned()
             .collect();
 
-        let mut old_resources: Vec<Resource> = resources
+        let mut old_resources: Vec<Resource> = construct(&self.last_peers)
             .into_iter()
             .filter(|resource| !added_peers.contains(resource.peer_uri()))
             .collect();
@@ -480,12 +1326,42 @@ mod tests {
 
     use std::time::Duration;
 
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+
+    use crate::config::PingEvery;
+    use crate::data::tar_data;
+    use crate::store::MemoryStore;
+
+    // Ping from `sender` that carries no news
+    fn test_ping(sender: &str) -> common::Ping {
+        common::Ping {
+            sender: sender.to_string(),
+            peers: vec![],
+            joined: vec![],
+            left: vec![],
+            version: 0,
+            ack: 0,
+            digest: 0,
+            incarnation: 0,
+            leaving: false,
+            weight: 1.0,
+            suspects: vec![],
+        }
+    }
+
     #[test]
     fn it_should_find_rebalance_resources() {
         let mut config = Config::new(vec![0], (0, 0));
         config.stable_delay = Duration::from_secs(0);
         config.replicate = 1;
-        let node = Node::new(SocketAddr::from(([157, 230, 95, 152], 8007)), config);
+        let node = Node::new(
+            SocketAddr::from(([157, 230, 95, 152], 8007)),
+            config,
+            Box::new(MemoryStore::new()),
+        );
 
         let peers: Vec<String> = [
             "http://157.230.95.152:80",
@@ -517,4 +1393,281 @@ mod tests {
             vec!["http://157.230.95.152:8002", "http://157.230.95.152:8007",]
         );
     }
+
+    #[test]
+    fn it_should_not_evict_owned_containers() {
+        let mut config = Config::new(vec![0], (0, 0));
+        config.memory_budget = Some(0);
+        let mut node = Node::new(
+            SocketAddr::from(([127, 0, 0, 1], 8000)),
+            config,
+            Box::new(MemoryStore::new()),
+        );
+
+        let data = Arc::new(tar_data(&[("index.html", b"hello")]));
+        node.data.put("container", data).expect("put to succeed");
+
+        // Without peers every container is owned by this node
+        assert!(node.evict().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_should_keep_query_in_directory_redirect() {
+        let mut node = Node::new(
+            SocketAddr::from(([127, 0, 0, 1], 8000)),
+            Config::new(vec![0], (0, 0)),
+            Box::new(MemoryStore::new()),
+        );
+
+        let data = Arc::new(tar_data(&[("docs/index.html", b"hello")]));
+        node.data.put("container", data).expect("put to succeed");
+
+        let response = node
+            .fetch(
+                "container",
+                "docs",
+                Some("page=2"),
+                true,
+                &hyper::HeaderMap::new(),
+            )
+            .wait()
+            .expect("fetch to succeed");
+        assert_eq!(response.status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers.get(hyper::header::LOCATION).unwrap(),
+            "/docs/?page=2"
+        );
+    }
+
+    #[test]
+    fn it_should_repair_queued_containers() {
+        let mut runtime = Runtime::new().expect("runtime to start");
+
+        // Remote peer that is missing the container, and accepts it
+        let stores = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let stores = stores.clone();
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(move || {
+                let stores = stores.clone();
+                hyper::service::service_fn_ok(move |req: hyper::Request<hyper::Body>| {
+                    let status = if req.method() == hyper::Method::PUT {
+                        stores.fetch_add(1, Ordering::SeqCst);
+                        StatusCode::CREATED
+                    } else {
+                        StatusCode::NOT_FOUND
+                    };
+                    hyper::Response::builder()
+                        .status(status)
+                        .body(hyper::Body::empty())
+                        .expect("response to build")
+                })
+            })
+        };
+        let peer = format!("http://{}", server.local_addr());
+        runtime.spawn(server.map_err(|err| panic!("server failed: {:?}", err)));
+
+        let mut config = Config::new(vec![0], (0, 0));
+        config.stable_delay = Duration::from_secs(0);
+        let mut node = Node::new(
+            SocketAddr::from(([127, 0, 0, 1], 8000)),
+            config,
+            Box::new(MemoryStore::new()),
+        );
+
+        // Peer becomes active after a couple of heartbeats
+        let ping = test_ping(&peer);
+        node.recv_ping(&ping).unwrap();
+        node.recv_ping(&ping).unwrap();
+
+        let data = Arc::new(tar_data(&[("index.html", b"hello")]));
+        node.data.put("container", data).expect("put to succeed");
+        node.repair_queue
+            .lock()
+            .expect("lock to acquire")
+            .insert("container".to_string());
+
+        runtime.block_on(node.repair()).expect("repair to finish");
+        assert_eq!(stores.load(Ordering::SeqCst), 1);
+        assert!(node
+            .repair_queue
+            .lock()
+            .expect("lock to acquire")
+            .is_empty());
+    }
+
+    #[test]
+    fn it_should_not_wait_for_silent_peers() {
+        let mut runtime = Runtime::new().expect("runtime to start");
+
+        // Silent peer accepts connections, and never answers
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").expect("listener to bind");
+        let silent = format!("http://{}", silent.local_addr().unwrap());
+
+        let mut healthy = vec![];
+        for _ in 0..2 {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("listener to bind");
+            let uri = format!("http://{}", listener.local_addr().unwrap());
+            let reply = serde_json::to_string(&test_ping(&uri)).unwrap();
+            let server = hyper::Server::from_tcp(listener)
+                .expect("server to start")
+                .serve(move || {
+                    let reply = reply.clone();
+                    hyper::service::service_fn_ok(move |_| {
+                        hyper::Response::new(hyper::Body::from(reply.clone()))
+                    })
+                });
+            runtime.spawn(server.map_err(|err| panic!("server failed: {:?}", err)));
+            healthy.push(uri);
+        }
+
+        let mut config = Config::new(vec![0], (0, 0));
+        config.stable_delay = Duration::from_secs(0);
+        config.ping_every = PingEvery {
+            min: Duration::from_millis(1),
+            max: Duration::from_millis(2),
+        };
+        let node = Arc::new(Mutex::new(Node::new(
+            SocketAddr::from(([127, 0, 0, 1], 8000)),
+            config,
+            Box::new(MemoryStore::new()),
+        )));
+
+        for uri in healthy.iter().chain(Some(&silent)) {
+            let mut node = node.lock().unwrap();
+            node.recv_ping(&test_ping(uri)).unwrap();
+            node.recv_ping(&test_ping(uri)).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(10));
+
+        let probes = node.lock().unwrap().send_pings();
+        assert_eq!(probes.len(), 3);
+        for (uri, probe) in probes {
+            let node = node.clone();
+            runtime.spawn(probe.then(move |result| {
+                node.lock().unwrap().recv_probe(&uri, result);
+                Ok(())
+            }));
+        }
+
+        runtime
+            .block_on(Delay::new(Instant::now() + Duration::from_millis(500)))
+            .unwrap();
+
+        // Answers of the healthy peers are applied while the silent one is
+        // still being probed, and it is not probed twice
+        let mut node = node.lock().unwrap();
+        let active = node.get_peer_uris();
+        assert!(healthy.iter().all(|uri| active.contains(uri)));
+        assert_eq!(node.probing.iter().collect::<Vec<_>>(), vec![&silent]);
+        assert!(node.send_pings().into_iter().all(|(uri, _)| uri != silent));
+    }
+
+    #[test]
+    fn it_should_refute_suspicion() {
+        let mut node = Node::new(
+            SocketAddr::from(([127, 0, 0, 1], 8000)),
+            Config::new(vec![0], (0, 0)),
+            Box::new(MemoryStore::new()),
+        );
+
+        let ping = common::Ping {
+            peers: vec!["http://127.0.0.1:8002".to_string()],
+            suspects: vec![
+                common::Suspect {
+                    uri: "http://127.0.0.1:8000".to_string(),
+                    incarnation: 0,
+                },
+                common::Suspect {
+                    uri: "http://127.0.0.1:8002".to_string(),
+                    incarnation: 0,
+                },
+            ],
+            ..test_ping("http://127.0.0.1:8001")
+        };
+        let reply = node.recv_ping(&ping).unwrap();
+
+        assert_eq!(reply.incarnation, 1);
+        assert_eq!(reply.suspects.len(), 1);
+        assert_eq!(reply.suspects[0].uri, "http://127.0.0.1:8002");
+    }
+
+    #[test]
+    fn it_should_send_peer_deltas() {
+        let mut node = Node::new(
+            SocketAddr::from(([127, 0, 0, 1], 8000)),
+            Config::new(vec![0], (0, 0)),
+            Box::new(MemoryStore::new()),
+        );
+
+        let ping = |peers: Vec<&str>, ack: u64, digest: u64| common::Ping {
+            peers: peers.into_iter().map(|uri| uri.to_string()).collect(),
+            version: 1,
+            ack,
+            digest,
+            ..test_ping("http://127.0.0.1:8001")
+        };
+
+        // Digest mismatch results in full sync
+        let reply = node
+            .recv_ping(&ping(vec!["http://127.0.0.1:8002"], 0, 0))
+            .unwrap();
+        assert_eq!(reply.ack, 0);
+        assert_eq!(reply.peers, vec!["http://127.0.0.1:8001"]);
+
+        let digest = node.members_digest();
+        let reply = node
+            .recv_ping(&ping(vec![], reply.version, digest))
+            .unwrap();
+        assert_eq!(reply.ack, 1);
+        assert!(reply.peers.is_empty());
+        assert!(reply.joined.is_empty());
+
+        // New active peer is sent as a delta
+        let version = reply.version;
+        let mut new_peer = ping(vec![], 0, 0);
+        new_peer.sender = "http://127.0.0.1:8003".to_string();
+        node.recv_ping(&new_peer).unwrap();
+
+        let digest = node.members_digest();
+        let reply = node.recv_ping(&ping(vec![], version, digest)).unwrap();
+        assert!(reply.peers.is_empty());
+        assert_eq!(reply.joined, vec!["http://127.0.0.1:8003"]);
+
+        // So is removed one
+        let version = reply.version;
+        node.remove_peer("http://127.0.0.1:8003".to_string());
+
+        let digest = node.members_digest();
+        let reply = node.recv_ping(&ping(vec![], version, digest)).unwrap();
+        assert_eq!(reply.ack, 1);
+        assert!(reply.peers.is_empty());
+        assert!(reply.joined.is_empty());
+        assert_eq!(reply.left, vec!["http://127.0.0.1:8003"]);
+    }
+
+    #[test]
+    fn it_should_announce_drain() {
+        let mut node = Node::new(
+            SocketAddr::from(([127, 0, 0, 1], 8000)),
+            Config::new(vec![0], (0, 0)),
+            Box::new(MemoryStore::new()),
+        );
+
+        let mut ping = test_ping("http://127.0.0.1:8001");
+        assert!(!node.recv_ping(&ping).unwrap().leaving);
+        assert_eq!(node.get_peer_uris().len(), 1);
+
+        // Leaving peer stops receiving requests
+        ping.leaving = true;
+        node.recv_ping(&ping).unwrap();
+        assert!(node.get_peer_uris().is_empty());
+
+        assert!(!node.is_drained());
+        let progress = node.start_drain();
+        assert_eq!(progress.remaining, 0);
+
+        // Peers should learn about departure first
+        assert!(!progress.done);
+        assert!(node.recv_ping(&ping).unwrap().leaving);
+    }
 }
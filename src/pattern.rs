@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+// Path pattern used by `_headers` and `_redirects` files. Segments are
+// matched literally, `:name` matches a single segment, and trailing `*`
+// matches the rest of the path (available as `splat`).
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(String),
+    Splat,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+pub type Captures = HashMap<String, String>;
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Pattern {
+        let parts: Vec<&str> = pattern.trim_start_matches('/').split('/').collect();
+        let last = parts.len() - 1;
+        let segments = parts
+            .into_iter()
+            .enumerate()
+            .map(|(i, segment)| {
+                // Splat is supported only at the end
+                if segment == "*" && i == last {
+                    Segment::Splat
+                } else if segment.starts_with(':') && segment.len() > 1 {
+                    Segment::Placeholder(segment[1..].to_string())
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect();
+        Pattern { segments }
+    }
+
+    pub fn matches(&self, path: &str) -> Option<Captures> {
+        let path = path.trim_start_matches('/');
+        let mut parts = path.splitn(self.segments.len(), '/');
+        let mut captures = Captures::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            let is_last = i == self.segments.len() - 1;
+            let part = match parts.next() {
+                Some(part) => part,
+                None => {
+                    // `/dir/*` matches `/dir` too
+                    if *segment == Segment::Splat {
+                        captures.insert("splat".to_string(), String::new());
+                        return Some(captures);
+                    }
+                    return None;
+                }
+            };
+
+            // Last part of `splitn` contains the rest of the path
+            if is_last && *segment != Segment::Splat && part.contains('/') {
+                return None;
+            }
+
+            match segment {
+                Segment::Literal(literal) => {
+                    if literal != part {
+                        return None;
+                    }
+                }
+                Segment::Placeholder(name) => {
+                    if part.is_empty() {
+                        return None;
+                    }
+                    captures.insert(name.clone(), part.to_string());
+                }
+                Segment::Splat => {
+                    captures.insert("splat".to_string(), part.to_string());
+                }
+            }
+        }
+        Some(captures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_match_patterns() {
+        let pattern = Pattern::parse("/assets/*");
+        assert!(pattern.matches("/assets/app.js").is_some());
+        assert_eq!(
+            pattern.matches("/assets/js/app.js").unwrap()["splat"],
+            "js/app.js"
+        );
+        assert!(pattern.matches("/assets").is_some());
+        assert!(pattern.matches("/other/app.js").is_none());
+
+        let pattern = Pattern::parse("/blog/:year/:slug");
+        let captures = pattern.matches("/blog/2019/hello").unwrap();
+        assert_eq!(captures["year"], "2019");
+        assert_eq!(captures["slug"], "hello");
+        assert!(pattern.matches("/blog/2019").is_none());
+        assert!(pattern.matches("/blog/2019/hello/world").is_none());
+
+        let pattern = Pattern::parse("/index.html");
+        assert!(pattern.matches("/index.html").is_some());
+        assert!(pattern.matches("/").is_none());
+
+        assert!(Pattern::parse("/*").matches("/").is_some());
+    }
+}
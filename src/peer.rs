@@ -1,5 +1,6 @@
 extern crate rand;
 
+use std::collections::VecDeque;
 use std::hash::{Hash, Hasher};
 use std::time::{Duration, Instant};
 
@@ -7,6 +8,13 @@ use rand::{thread_rng, Rng};
 
 use crate::config::Config;
 
+// Number of ping inter-arrival times used by the failure detector
+const HEARTBEAT_WINDOW: usize = 100;
+
+// Lower bound of the standard deviation, so that perfectly regular pings
+// do not make the detector overly sensitive
+const MIN_STD_DEVIATION: f64 = 0.1;
+
 #[derive(Debug, Clone)]
 pub struct Peer {
     config: Config,
@@ -14,8 +22,26 @@ pub struct Peer {
     uri: String,
     ping_at: Instant,
     stable_at: Instant,
-    inactive_at: Instant,
     remove_at: Instant,
+
+    // Phi-accrual failure detector state, see `phi`
+    last_heartbeat: Option<Instant>,
+    intervals: VecDeque<f64>,
+
+    // Latest known incarnation of the peer, and when it was suspected to
+    // have failed in it
+    incarnation: u64,
+    suspected_at: Option<Instant>,
+
+    // Version of the peer's peer set applied by us, and version of ours
+    // applied by the peer. Zero requests full sync.
+    seen_version: u64,
+    acked_version: u64,
+
+    leaving: bool,
+
+    // Relative capacity advertised by the peer
+    weight: f64,
 }
 
 impl Peer {
@@ -27,18 +53,32 @@ impl Peer {
 
         // The rest are regular
         let stable_at = now + config.stable_delay;
+        let remove_at = now + config.remove_timeout;
 
         // Until the peer pings - it should be treated as inactive
-        let inactive_at = now - config.ping_every.max;
-        let remove_at = now + config.remove_timeout;
+        let last_heartbeat = None;
+
+        // Expect pings at the average rate until there are enough samples
+        let min = config.ping_every.min.as_secs_f64();
+        let max = config.ping_every.max.as_secs_f64();
+        let mut intervals = VecDeque::with_capacity(HEARTBEAT_WINDOW);
+        intervals.push_back(min);
+        intervals.push_back(max);
 
         Self {
             config,
             uri,
             ping_at,
             stable_at,
-            inactive_at,
             remove_at,
+            last_heartbeat,
+            intervals,
+            incarnation: 0,
+            suspected_at: None,
+            seen_version: 0,
+            acked_version: 0,
+            leaving: false,
+            weight: 1.0,
         }
     }
 
@@ -65,8 +105,16 @@ impl Peer {
         let now = Instant::now();
         let was_active = self.is_active(now);
 
+        if let Some(last) = self.last_heartbeat {
+            if self.intervals.len() == HEARTBEAT_WINDOW {
+                self.intervals.pop_front();
+            }
+            self.intervals
+                .push_back(now.duration_since(last).as_secs_f64());
+        }
+        self.last_heartbeat = Some(now);
+
         self.remove_at = now + self.config.alive_timeout + self.config.remove_timeout;
-        self.inactive_at = now + self.config.alive_timeout;
         self.ping_at = now + Peer::ping_delay(&self.config);
 
         // Re-instantiate stable delay
@@ -75,6 +123,73 @@ impl Peer {
         }
     }
 
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    pub fn is_suspected(&self) -> bool {
+        self.suspected_at.is_some()
+    }
+
+    // Newer incarnation refutes suspicion of the older ones
+    pub fn observe_incarnation(&mut self, incarnation: u64) {
+        if incarnation <= self.incarnation {
+            return;
+        }
+
+        self.incarnation = incarnation;
+        if self.suspected_at.take().is_some() {
+            trace!("peer: {} refuted suspicion", self.uri);
+        }
+    }
+
+    pub fn suspect(&mut self, incarnation: u64, now: Instant) {
+        // Suspicion of a newer incarnation replaces the existing one
+        if incarnation < self.incarnation
+            || (incarnation == self.incarnation && self.suspected_at.is_some())
+        {
+            return;
+        }
+
+        trace!("suspect peer: {} incarnation: {}", self.uri, incarnation);
+        self.incarnation = incarnation;
+        self.suspected_at = Some(now);
+    }
+
+    pub fn seen_version(&self) -> u64 {
+        self.seen_version
+    }
+
+    pub fn set_seen_version(&mut self, version: u64) {
+        self.seen_version = version;
+    }
+
+    pub fn acked_version(&self) -> u64 {
+        self.acked_version
+    }
+
+    pub fn set_acked_version(&mut self, version: u64) {
+        self.acked_version = version;
+    }
+
+    // Leaving peer stays known, but does not get any requests
+    pub fn set_leaving(&mut self, leaving: bool) {
+        if leaving && !self.leaving {
+            trace!("peer: {} is leaving", self.uri);
+        }
+        self.leaving = leaving;
+    }
+
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    pub fn set_weight(&mut self, weight: f64) {
+        if weight.is_finite() && weight > 0.0 {
+            self.weight = weight;
+        }
+    }
+
     pub fn should_remove(&self, now: Instant) -> bool {
         self.remove_at <= now
     }
@@ -87,8 +202,49 @@ impl Peer {
         self.stable_at <= now
     }
 
+    // Suspicion level that the peer has failed, given the distribution of
+    // previous ping inter-arrival times. `phi = 1` means ~10% chance of a
+    // false positive, `phi = 2` ~1%, and so on.
+    pub fn phi(&self, now: Instant) -> Option<f64> {
+        let last = self.last_heartbeat?;
+        let elapsed = now.duration_since(last).as_secs_f64();
+
+        let len = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / len;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|interval| (interval - mean) * (interval - mean))
+            .sum::<f64>()
+            / len;
+        let std_deviation = variance.sqrt().max(MIN_STD_DEVIATION);
+
+        // Logistic approximation of the normal cumulative distribution
+        let y = (elapsed - mean) / std_deviation;
+        let e = (-y * (1.5976 + 0.070_566 * y * y)).exp();
+        let p = if elapsed > mean {
+            e / (1.0 + e)
+        } else {
+            1.0 - 1.0 / (1.0 + e)
+        };
+        Some(-p.max(f64::MIN_POSITIVE).log10())
+    }
+
     pub fn is_active(&self, now: Instant) -> bool {
-        now < self.inactive_at
+        if self.leaving {
+            return false;
+        }
+
+        if let Some(suspected_at) = self.suspected_at {
+            if suspected_at + self.config.suspect_timeout <= now {
+                return false;
+            }
+        }
+
+        match self.phi(now) {
+            Some(phi) => phi < self.config.phi_threshold,
+            None => false,
+        }
     }
 }
 
@@ -105,3 +261,70 @@ impl Hash for Peer {
         self.uri.hash(state);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_grow_suspicion_with_silence() {
+        let mut config = Config::new(vec![0], (0, 0));
+        config.phi_threshold = 8.0;
+        let mut peer = Peer::new("http://127.0.0.1:8000".to_string(), config);
+
+        let now = Instant::now();
+        assert!(!peer.is_active(now));
+        assert!(peer.phi(now).is_none());
+
+        peer.mark_alive();
+        let last = peer.last_heartbeat.unwrap();
+        for _ in 0..10 {
+            peer.intervals.push_back(1.0);
+        }
+
+        let early = peer.phi(last + Duration::from_millis(500)).unwrap();
+        let late = peer.phi(last + Duration::from_secs(3)).unwrap();
+        let dead = peer.phi(last + Duration::from_secs(60)).unwrap();
+        assert!(early < late && late < dead);
+        assert!(peer.is_active(last + Duration::from_millis(500)));
+        assert!(!peer.is_active(last + Duration::from_secs(60)));
+        assert!(dead.is_finite());
+    }
+
+    #[test]
+    fn it_should_refute_suspicion() {
+        let mut config = Config::new(vec![0], (0, 0));
+        config.suspect_timeout = Duration::from_millis(500);
+        let mut peer = Peer::new("http://127.0.0.1:8000".to_string(), config);
+        peer.mark_alive();
+
+        let now = peer.last_heartbeat.unwrap();
+        peer.observe_incarnation(2);
+
+        // Stale suspicion is ignored
+        peer.suspect(1, now);
+        assert!(!peer.is_suspected());
+
+        peer.suspect(2, now);
+        assert!(peer.is_suspected());
+        assert!(peer.is_active(now));
+        assert!(!peer.is_active(now + Duration::from_millis(600)));
+
+        peer.observe_incarnation(2);
+        assert!(peer.is_suspected());
+        peer.observe_incarnation(3);
+        assert!(!peer.is_suspected());
+        assert!(peer.is_active(now + Duration::from_millis(600)));
+
+        // Suspicion of a newer incarnation restarts the timeout
+        peer.suspect(3, now);
+        peer.suspect(3, now + Duration::from_millis(400));
+        assert!(!peer.is_active(now + Duration::from_millis(600)));
+        peer.suspect(4, now + Duration::from_millis(400));
+        assert!(peer.is_active(now + Duration::from_millis(600)));
+        assert!(!peer.is_active(now + Duration::from_millis(1000)));
+
+        peer.observe_incarnation(4);
+        assert!(peer.is_suspected());
+    }
+}
@@ -0,0 +1,102 @@
+// Parsing of `Range: bytes=...` request header, see RFC 7233
+
+// Too many ranges are likely an attempt to amplify the response
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum Range {
+    // Header is missing or invalid, whole content should be sent
+    Full,
+    // Inclusive byte ranges
+    Partial(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+pub fn parse(header: &str, len: u64) -> Range {
+    let header = header.trim();
+    if !header.starts_with("bytes=") {
+        return Range::Full;
+    }
+
+    let specs: Vec<&str> = header["bytes=".len()..]
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return Range::Full;
+    }
+
+    let mut ranges = vec![];
+    for spec in specs {
+        let mut parts = spec.splitn(2, '-');
+        let (start, end) = match (parts.next(), parts.next()) {
+            (Some(start), Some(end)) => (start.trim(), end.trim()),
+            _ => return Range::Full,
+        };
+
+        let range = if start.is_empty() {
+            // Suffix range: last `end` bytes
+            let suffix: u64 = match end.parse() {
+                Ok(suffix) => suffix,
+                Err(_) => return Range::Full,
+            };
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            (len.saturating_sub(suffix), len - 1)
+        } else {
+            let start: u64 = match start.parse() {
+                Ok(start) => start,
+                Err(_) => return Range::Full,
+            };
+            let end: u64 = if end.is_empty() {
+                u64::MAX
+            } else {
+                match end.parse() {
+                    Ok(end) => end,
+                    Err(_) => return Range::Full,
+                }
+            };
+            if end < start {
+                return Range::Full;
+            }
+            if start >= len {
+                continue;
+            }
+            (start, std::cmp::min(end, len - 1))
+        };
+        ranges.push(range);
+    }
+
+    if ranges.is_empty() {
+        Range::Unsatisfiable
+    } else {
+        Range::Partial(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_ranges() {
+        assert_eq!(parse("bytes=0-9", 100), Range::Partial(vec![(0, 9)]));
+        assert_eq!(parse("bytes=90-", 100), Range::Partial(vec![(90, 99)]));
+        assert_eq!(parse("bytes=-10", 100), Range::Partial(vec![(90, 99)]));
+        assert_eq!(parse("bytes=-200", 100), Range::Partial(vec![(0, 99)]));
+        assert_eq!(parse("bytes=50-200", 100), Range::Partial(vec![(50, 99)]));
+        assert_eq!(
+            parse("bytes=0-0, 10-19", 100),
+            Range::Partial(vec![(0, 0), (10, 19)])
+        );
+
+        assert_eq!(parse("bytes=100-", 100), Range::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 100), Range::Unsatisfiable);
+
+        assert_eq!(parse("items=0-9", 100), Range::Full);
+        assert_eq!(parse("bytes=9-0", 100), Range::Full);
+        assert_eq!(parse("bytes=a-b", 100), Range::Full);
+    }
+}
@@ -0,0 +1,164 @@
+extern crate hyper;
+
+use hyper::StatusCode;
+
+use crate::error::Error;
+use crate::pattern::{Captures, Pattern};
+
+// Redirect and rewrite rules from `_redirects` file in the container:
+//
+//   # Comment
+//   /old               /new                301
+//   /blog/:year/:slug  /posts/:year/:slug
+//   /docs/*            https://docs.example.com/:splat  302
+//   /app/*             /app/index.html     200
+//
+// Status defaults to 301, `200` rewrites the path without redirecting the
+// client. First matching rule wins.
+
+#[derive(Debug, PartialEq)]
+pub enum Redirect {
+    // Serve a different file from the same container
+    Rewrite(String),
+    Redirect(StatusCode, String),
+}
+
+struct Rule {
+    pattern: Pattern,
+    to: String,
+    status: StatusCode,
+}
+
+#[derive(Default)]
+pub struct RedirectRules {
+    rules: Vec<Rule>,
+}
+
+impl RedirectRules {
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let mut rules = vec![];
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let bad_line = || Error::Manifest(format!("_redirects line {}: {}", i + 1, line));
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let (from, to, status) = match parts.as_slice() {
+                [from, to] => (from, to, StatusCode::MOVED_PERMANENTLY),
+                [from, to, status] => {
+                    let status = status.parse::<u16>().map_err(|_| bad_line())?;
+                    (
+                        from,
+                        to,
+                        StatusCode::from_u16(status).map_err(|_| bad_line())?,
+                    )
+                }
+                _ => return Err(bad_line()),
+            };
+
+            if !from.starts_with('/') {
+                return Err(bad_line());
+            }
+
+            match status {
+                // Rewrites cannot leave the container
+                StatusCode::OK if !to.starts_with('/') => return Err(bad_line()),
+                StatusCode::OK
+                | StatusCode::MOVED_PERMANENTLY
+                | StatusCode::FOUND
+                | StatusCode::SEE_OTHER
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT => {}
+                _ => return Err(bad_line()),
+            }
+
+            rules.push(Rule {
+                pattern: Pattern::parse(from),
+                to: to.to_string(),
+                status,
+            });
+        }
+
+        Ok(RedirectRules { rules })
+    }
+
+    pub fn resolve(&self, path: &str) -> Option<Redirect> {
+        self.rules.iter().find_map(|rule| {
+            let captures = rule.pattern.matches(path)?;
+            let to = substitute(&rule.to, &captures);
+            if rule.status == StatusCode::OK {
+                Some(Redirect::Rewrite(to))
+            } else {
+                Some(Redirect::Redirect(rule.status, to))
+            }
+        })
+    }
+}
+
+// Replace `:name` segments of destination with captured values
+fn substitute(to: &str, captures: &Captures) -> String {
+    to.split('/')
+        .map(|segment| {
+            let mut parts = segment.splitn(2, ':');
+            match (
+                parts.next(),
+                parts.next().and_then(|name| captures.get(name)),
+            ) {
+                (Some(""), Some(value)) => value.as_str(),
+                _ => segment,
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_resolve_redirects() {
+        let rules = RedirectRules::parse(
+            "# Moved pages\n\
+             /old /new\n\
+             /blog/:year/:slug /posts/:year/:slug 302\n\
+             /docs/* https://docs.example.com/:splat 307\n\
+             /app/* /app/index.html 200\n",
+        )
+        .expect("rules to parse");
+
+        assert_eq!(
+            rules.resolve("/old"),
+            Some(Redirect::Redirect(
+                StatusCode::MOVED_PERMANENTLY,
+                "/new".to_string()
+            ))
+        );
+        assert_eq!(
+            rules.resolve("/blog/2019/hello"),
+            Some(Redirect::Redirect(
+                StatusCode::FOUND,
+                "/posts/2019/hello".to_string()
+            ))
+        );
+        assert_eq!(
+            rules.resolve("/docs/guide/intro"),
+            Some(Redirect::Redirect(
+                StatusCode::TEMPORARY_REDIRECT,
+                "https://docs.example.com/guide/intro".to_string()
+            ))
+        );
+        assert_eq!(
+            rules.resolve("/app/settings"),
+            Some(Redirect::Rewrite("/app/index.html".to_string()))
+        );
+        assert_eq!(rules.resolve("/index.html"), None);
+
+        assert!(RedirectRules::parse("/a").is_err());
+        assert!(RedirectRules::parse("/a /b 404").is_err());
+        assert!(RedirectRules::parse("/a https://example.com 200").is_err());
+    }
+}
@@ -1,4 +1,5 @@
 extern crate futures;
+extern crate hyper;
 extern crate siphasher;
 
 use std::cmp::Ordering;
@@ -15,18 +16,25 @@ use crate::message::response;
 
 type FutureFetch = Box<Future<Item = response::Fetch, Error = Error> + Send>;
 
-#[derive(Eq, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct Resource {
     peer_uri: String,
     store_uri: String,
     container: String,
     hash: u64,
+    score: f64,
     local: bool,
 }
 
+// Higher scores come first, so that sorted resources start with the peers
+// that should hold the container
 impl Ord for Resource {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.hash.cmp(&other.hash)
+        other
+            .score
+            .partial_cmp(&self.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| self.hash.cmp(&other.hash))
     }
 }
 
@@ -42,6 +50,8 @@ impl PartialEq for Resource {
     }
 }
 
+impl Eq for Resource {}
+
 impl Hash for Resource {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.store_uri.hash(state);
@@ -49,22 +59,40 @@ impl Hash for Resource {
 }
 
 impl Resource {
-    pub fn new(peer_uri: &str, container: &str, local: bool, hash_seed: (u64, u64)) -> Resource {
+    // Peer gets a share of containers proportional to its `weight`
+    pub fn new(
+        peer_uri: &str,
+        container: &str,
+        local: bool,
+        hash_seed: (u64, u64),
+        weight: f64,
+    ) -> Resource {
         let mut hasher = SipHasher::new_with_keys(hash_seed.0, hash_seed.1);
 
         let peer_uri = peer_uri.to_string();
         let store_uri = format!("{}/{}", peer_uri, container);
 
         hasher.write(store_uri.as_bytes());
+        let hash = hasher.finish();
+
         Resource {
             peer_uri,
             store_uri,
             container: container.to_string(),
             local,
-            hash: hasher.finish(),
+            hash,
+            score: Resource::score(hash, weight),
         }
     }
 
+    // Weighted rendezvous hashing: `-weight / ln(h)` with `h` uniform in
+    // (0, 1). Lower hashes map to higher `h`, so that equally weighted peers
+    // are ordered by hash.
+    fn score(hash: u64, weight: f64) -> f64 {
+        let x = (hash as f64 + 0.5) / 2f64.powi(64);
+        -weight / (-x).ln_1p()
+    }
+
     pub fn peer_uri(&self) -> &str {
         &self.peer_uri
     }
@@ -73,12 +101,20 @@ impl Resource {
         self.local
     }
 
-    pub fn fetch(&self, client: &Client, uri: &str) -> FutureFetch {
+    pub fn fetch(&self, client: &Client, uri: &str, headers: &hyper::HeaderMap) -> FutureFetch {
         if self.local {
             return Box::new(future::err(Error::NotFound));
         }
 
-        client.fetch(&self.peer_uri, &self.container, uri)
+        client.fetch(&self.peer_uri, &self.container, uri, headers)
+    }
+
+    pub fn peek(&self, client: &Client) -> Box<Future<Item = (), Error = Error> + Send> {
+        if self.local {
+            return Box::new(future::err(Error::NotFound));
+        }
+
+        client.peek(&self.peer_uri, &self.container)
     }
 
     pub fn store(
@@ -94,3 +130,44 @@ impl Resource {
         client.store(&self.peer_uri, &self.container, data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn it_should_place_proportionally_to_weight() {
+        let mut owners: HashMap<String, usize> = HashMap::new();
+        for i in 0..4000 {
+            let container = format!("container-{}", i);
+            let mut resources = vec![
+                Resource::new("http://127.0.0.1:8000", &container, false, (0, 0), 1.0),
+                Resource::new("http://127.0.0.1:8001", &container, false, (0, 0), 3.0),
+            ];
+            resources.sort();
+            *owners
+                .entry(resources[0].peer_uri().to_string())
+                .or_default() += 1;
+        }
+
+        let large = owners["http://127.0.0.1:8001"] as f64 / 4000.0;
+        assert!(large > 0.7 && large < 0.8, "share: {}", large);
+
+        // Equal weights keep plain hash order
+        let mut resources: Vec<Resource> = (0..8)
+            .map(|i| {
+                Resource::new(
+                    &format!("http://127.0.0.1:{}", 8000 + i),
+                    "c",
+                    false,
+                    (0, 0),
+                    2.0,
+                )
+            })
+            .collect();
+        resources.sort();
+        assert!(resources.windows(2).all(|pair| pair[0].hash < pair[1].hash));
+    }
+}
@@ -0,0 +1,278 @@
+extern crate bytes;
+extern crate futures;
+extern crate httpdate;
+extern crate hyper;
+extern crate rand;
+
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use futures::stream;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, StatusCode};
+use rand::{thread_rng, Rng};
+
+use crate::data::DataFile;
+use crate::error::Error;
+use crate::message::response;
+use crate::range::{self, Range};
+
+fn get_header(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|val| val.to_str().ok())
+}
+
+fn header_value(value: String) -> Result<HeaderValue, Error> {
+    HeaderValue::from_str(&value).map_err(|err| Error::from(hyper::http::Error::from(err)))
+}
+
+fn parse_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value).ok()
+}
+
+// Weak comparison of entity tags
+fn etag_matches(list: &str, etag: &str) -> bool {
+    list.split(',')
+        .map(str::trim)
+        .map(|tag| tag.trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+fn is_not_modified(file: &DataFile, etag: &str, request: &HeaderMap) -> bool {
+    // `If-Modified-Since` is ignored when `If-None-Match` is present
+    if let Some(if_none_match) = get_header(request, header::IF_NONE_MATCH) {
+        return etag_matches(if_none_match, etag);
+    }
+
+    let since = get_header(request, header::IF_MODIFIED_SINCE).and_then(parse_date);
+    match (since, file.modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+// `If-Range` requires strong comparison against the uncompressed
+// representation
+fn if_range_matches(file: &DataFile, if_range: &str) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') {
+        return if_range == file.etag(None);
+    }
+
+    match (parse_date(if_range), file.modified) {
+        (Some(date), Some(modified)) => date == modified,
+        _ => false,
+    }
+}
+
+// Build response for a file stored on this node
+pub fn serve_file(
+    peer: &str,
+    file: &DataFile,
+    request: &HeaderMap,
+) -> Result<response::Fetch, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+
+    // Send the whole file if it has changed since `If-Range`
+    let range = match get_header(request, header::IF_RANGE) {
+        Some(if_range) if !if_range_matches(file, if_range) => None,
+        _ => get_header(request, header::RANGE),
+    };
+
+    // Ranges are always served from uncompressed content
+    let accept_encoding = if range.is_some() {
+        ""
+    } else {
+        get_header(request, header::ACCEPT_ENCODING).unwrap_or("")
+    };
+
+    let (encoding, content) = file.encode(accept_encoding)?;
+    if let Some(encoding) = encoding {
+        headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+    }
+
+    let etag = file.etag(encoding);
+    headers.insert(header::ETAG, header_value(etag.clone())?);
+    if let Some(modified) = file.modified {
+        headers.insert(
+            header::LAST_MODIFIED,
+            header_value(httpdate::fmt_http_date(modified))?,
+        );
+    }
+
+    if is_not_modified(file, &etag, request) {
+        headers.remove(header::CONTENT_ENCODING);
+        return Ok(response::Fetch {
+            peer: peer.to_string(),
+            status: StatusCode::NOT_MODIFIED,
+            mime: file.mime.clone(),
+            headers,
+            body: Body::empty(),
+        });
+    }
+
+    let len = content.len() as u64;
+    let range = range
+        .map(|range| range::parse(range, len))
+        .unwrap_or(Range::Full);
+
+    let mut mime = file.mime.clone();
+    let (status, body) = match range {
+        Range::Full => (StatusCode::OK, Body::from(content)),
+        Range::Unsatisfiable => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                header_value(format!("bytes */{}", len))?,
+            );
+            (StatusCode::RANGE_NOT_SATISFIABLE, Body::empty())
+        }
+        Range::Partial(ref ranges) if ranges.len() == 1 => {
+            let (start, end) = ranges[0];
+            headers.insert(
+                header::CONTENT_RANGE,
+                header_value(format!("bytes {}-{}/{}", start, end, len))?,
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                Body::from(content.slice(start as usize, end as usize + 1)),
+            )
+        }
+        Range::Partial(ranges) => {
+            let boundary = format!("{:016x}", thread_rng().gen::<u64>());
+
+            let mut parts: Vec<Bytes> = Vec::with_capacity(ranges.len() * 2 + 1);
+            for (start, end) in ranges {
+                parts.push(Bytes::from(format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, mime, start, end, len
+                )));
+                parts.push(content.slice(start as usize, end as usize + 1));
+            }
+            parts.push(Bytes::from(format!("\r\n--{}--\r\n", boundary)));
+
+            mime = format!("multipart/byteranges; boundary={}", boundary);
+            (
+                StatusCode::PARTIAL_CONTENT,
+                Body::wrap_stream(stream::iter_ok::<_, Error>(parts)),
+            )
+        }
+    };
+
+    Ok(response::Fetch {
+        peer: peer.to_string(),
+        status,
+        mime,
+        headers,
+        body,
+    })
+}
+
+// Build redirect response, see `_redirects`
+pub fn serve_redirect(
+    peer: &str,
+    status: StatusCode,
+    location: &str,
+) -> Result<response::Fetch, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::LOCATION, header_value(location.to_string())?);
+
+    Ok(response::Fetch {
+        peer: peer.to_string(),
+        status,
+        mime: "text/plain".to_string(),
+        headers,
+        body: Body::empty(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::data::{tar_data, Data};
+
+    fn data() -> Data {
+        tar_data(&[("file.bin", b"0123456789")])
+    }
+
+    fn request(headers: &[(HeaderName, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn it_should_serve_conditional_requests() {
+        let data = data();
+        let file = data.serve("file.bin").unwrap();
+        let etag = file.etag(None);
+        assert_eq!(
+            file.modified,
+            Some(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+        );
+
+        let res = serve_file("", file, &request(&[])).unwrap();
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers[header::ETAG], etag.as_str());
+
+        let res = serve_file("", file, &request(&[(header::IF_NONE_MATCH, &etag)])).unwrap();
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+
+        let since = httpdate::fmt_http_date(file.modified.unwrap());
+        let res = serve_file("", file, &request(&[(header::IF_MODIFIED_SINCE, &since)])).unwrap();
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+
+        let res = serve_file(
+            "",
+            file,
+            &request(&[
+                (header::IF_NONE_MATCH, "\"other\""),
+                (header::IF_MODIFIED_SINCE, &since),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    #[test]
+    fn it_should_serve_ranges() {
+        let data = data();
+        let file = data.serve("file.bin").unwrap();
+
+        let res = serve_file("", file, &request(&[(header::RANGE, "bytes=2-4")])).unwrap();
+        assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers[header::CONTENT_RANGE], "bytes 2-4/10");
+
+        let res = serve_file("", file, &request(&[(header::RANGE, "bytes=20-")])).unwrap();
+        assert_eq!(res.status, StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let res = serve_file(
+            "",
+            file,
+            &request(&[
+                (header::RANGE, "bytes=2-4"),
+                (header::IF_RANGE, "\"stale\""),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(res.status, StatusCode::OK);
+
+        let etag = file.etag(None);
+        let res = serve_file(
+            "",
+            file,
+            &request(&[(header::RANGE, "bytes=0-0,5-"), (header::IF_RANGE, &etag)]),
+        )
+        .unwrap();
+        assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+        assert!(res.mime.starts_with("multipart/byteranges"));
+    }
+}
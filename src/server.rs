@@ -6,19 +6,26 @@ extern crate env_logger;
 
 use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use futures::future;
 use futures::prelude::*;
-use tokio::timer::Interval;
+use futures::stream;
+use tokio::timer::{Delay, Interval};
 
+use crate::client::Client;
 use crate::config::Config;
 use crate::error::Error;
 use crate::node::Node;
 use crate::service::*;
+use crate::store::{self, ContainerStore};
+
+// How often the progress of remote drain is checked
+const DRAIN_POLL_EVERY: Duration = Duration::from_secs(1);
 
 pub struct Server {
     config: Config,
+    store: Option<Box<ContainerStore>>,
 }
 
 impl Server {
@@ -26,10 +33,24 @@ impl Server {
         trace!("auth header: {}", config.get_auth());
         trace!("config: {:#?}", config);
 
-        Server { config }
+        Server {
+            config,
+            store: None,
+        }
+    }
+
+    // Use custom container store instead of the one described by config
+    pub fn with_store(config: Config, store: Box<ContainerStore>) -> Server {
+        let mut server = Server::new(config);
+        server.store = Some(store);
+        server
     }
 
-    pub fn listen(&self, port: u16, host: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+    pub fn listen(
+        &mut self,
+        port: u16,
+        host: &str,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
         let ip_addr: IpAddr = match host.parse().map_err(Error::from) {
             Ok(addr) => addr,
             Err(err) => {
@@ -40,7 +61,17 @@ impl Server {
 
         let builder = hyper::Server::bind(&bind_addr);
 
-        let mut node = Node::new(bind_addr, self.config.clone());
+        let store = match self.store.take() {
+            Some(store) => store,
+            None => match store::from_config(&self.config) {
+                Ok(store) => store,
+                Err(err) => {
+                    return Box::new(future::err(err));
+                }
+            },
+        };
+
+        let mut node = Node::new(bind_addr, self.config.clone(), store);
 
         // Add initial peers
         for peer in self.config.initial_peers.iter() {
@@ -62,26 +93,20 @@ impl Server {
         let ping = Interval::new(Instant::now(), self.config.ping_every.min)
             .from_err::<Error>()
             .for_each(move |_| {
-                let node = ping_node.clone();
-
-                ping_node
-                    .lock()
-                    .expect("lock to acquire")
-                    .send_pings()
-                    .and_then(|pings| {
-                        pings
-                            .into_iter()
-                            .filter_map(|ping| ping)
-                            .for_each(move |ping| {
-                                node.lock()
-                                    .expect("lock to acquire")
-                                    .recv_ping(&ping)
-                                    // Ignore errors
-                                    .map(|_| ())
-                                    .unwrap_or(());
-                            });
+                let probes = ping_node.lock().expect("lock to acquire").send_pings();
+
+                // Probes run on their own, so that slow and indirect probes of
+                // failed peers do not hold back heartbeats to the others
+                for (uri, probe) in probes {
+                    let node = ping_node.clone();
+                    tokio::spawn(probe.then(move |result| {
+                        node.lock()
+                            .expect("lock to acquire")
+                            .recv_probe(&uri, result);
                         Ok(())
-                    })
+                    }));
+                }
+                Ok(())
             });
 
         let rebalance_node = node.clone();
@@ -89,6 +114,7 @@ impl Server {
             .from_err::<Error>()
             .for_each(move |_| {
                 let remove_keys_node = rebalance_node.clone();
+                let evict_node = rebalance_node.clone();
 
                 rebalance_node
                     .lock()
@@ -100,10 +126,97 @@ impl Server {
                             .expect("lock to acquire")
                             .remove(obsolete_keys);
                     })
+                    .and_then(move |_| {
+                        let remove_keys_node = evict_node.clone();
+
+                        evict_node.lock().expect("lock to acquire").evict().map(
+                            move |evicted_keys| {
+                                remove_keys_node
+                                    .lock()
+                                    .expect("lock to acquire")
+                                    .remove(evicted_keys);
+                            },
+                        )
+                    })
             });
 
+        let repair_node = node.clone();
+        let repair = Interval::new(Instant::now(), self.config.repair_every)
+            .from_err::<Error>()
+            .for_each(move |_| repair_node.lock().expect("lock to acquire").repair());
+
+        // Resolves once the node has left the cluster
+        let drain_node = node.clone();
+        let drained_node = node.clone();
+        let drain = Interval::new(Instant::now(), self.config.repair_every)
+            .from_err::<Error>()
+            .take_while(move |_| {
+                let is_drained = drained_node.lock().expect("lock to acquire").is_drained();
+                if is_drained {
+                    trace!("drained, shutting down");
+                }
+                Ok(!is_drained)
+            })
+            .for_each(move |_| drain_node.lock().expect("lock to acquire").drain());
+
         trace!("Listening on {:?}", server.local_addr());
 
-        Box::new(server.from_err().join(ping).join(rebalance).map(|_| ()))
+        Box::new(
+            server
+                .from_err()
+                .join(ping)
+                .join(rebalance)
+                .join(repair)
+                .map(|_| ())
+                .select(drain)
+                .map(|_| ())
+                .map_err(|(err, _)| err),
+        )
+    }
+
+    // Ask the node at `uri` to leave the cluster and follow its progress.
+    // Yields numbers of remaining and all containers, ends when the node is
+    // done.
+    pub fn drain(
+        config: &Config,
+        uri: &str,
+    ) -> Box<Stream<Item = (usize, usize), Error = Error> + Send> {
+        let client = Client::new(config, "");
+        let uri = uri.to_string();
+
+        // State is `None` once the node is done, and otherwise tells if it
+        // had nothing left to hand off on the last poll
+        let progress = stream::unfold(Some(false), move |state| {
+            let handed_off = state?;
+
+            let client = client.clone();
+            let uri = uri.clone();
+            let delay = Instant::now() + DRAIN_POLL_EVERY;
+            Some(
+                Delay::new(delay)
+                    .map_err(|_| Error::TimerError)
+                    .and_then(move |_| client.drain(&uri))
+                    .then(move |result| match result {
+                        Ok(progress) => {
+                            let state = if progress.done {
+                                None
+                            } else {
+                                Some(progress.remaining == 0)
+                            };
+                            Ok((Some((progress.remaining, progress.containers)), state))
+                        }
+
+                        // Drained node exits on its own, possibly before the
+                        // next poll
+                        Err(ref err) if handed_off => {
+                            trace!("drain: node is gone after handoff: {:?}", err);
+                            Ok((None, None))
+                        }
+                        Err(err) => Err(err),
+                    }),
+            )
+        });
+
+        Box::new(progress.filter_map(|progress| progress))
     }
 }
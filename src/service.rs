@@ -10,23 +10,78 @@ use futures::future::{self, FutureResult};
 use futures::prelude::*;
 use futures::IntoFuture;
 use hmac::{Hmac, Mac};
-use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use sha2::{Digest, Sha256};
 
 use crate::config::Config;
+use crate::data::{Archive, ArchiveParser, Compression, Data, Limits};
 use crate::error::Error;
 use crate::message::response;
 use crate::node::Node;
 
 type HmacSha256 = Hmac<Sha256>;
 
+// Memory reserved for upload up front, announced size is not trusted
+const MAX_UPLOAD_CAPACITY: u64 = 1024 * 1024;
+
+// Archive is detected from the first bytes, magic of xz is the longest
+const MAGIC_SIZE: usize = 6;
+
 const CONTAINER_ALPHABET: &[char] = &[
     '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i',
     'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
 ];
 
+// Upload whose archive format is known once its first bytes arrive
+struct Upload {
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    limits: Limits,
+    capacity: usize,
+    // First bytes, until there are enough to detect the format
+    head: Vec<u8>,
+    parser: Option<ArchiveParser>,
+}
+
+impl Upload {
+    fn push(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        if let Some(ref mut parser) = self.parser {
+            return parser.push(chunk);
+        }
+
+        self.head.extend_from_slice(chunk);
+        if self.head.len() >= MAGIC_SIZE {
+            self.start()?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        let archive = RPCService::detect_archive(
+            self.content_type.take(),
+            self.content_encoding.take(),
+            &self.head,
+        )?;
+        let mut parser = ArchiveParser::new(archive, self.limits, self.capacity)?;
+        parser.push(&self.head)?;
+        self.head = vec![];
+        self.parser = Some(parser);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Data, Error> {
+        if self.parser.is_none() {
+            self.start()?;
+        }
+        match self.parser {
+            Some(parser) => parser.finish(),
+            None => Err(Error::Unreachable),
+        }
+    }
+}
+
 pub struct RPCService {
     config: Config,
     node: Arc<Mutex<Node>>,
@@ -52,10 +107,54 @@ impl RPCService {
             .and_then(|chunk| serde_json::from_slice::<T>(&chunk).map_err(Error::from))
     }
 
-    fn fetch_raw(body: Body) -> impl Future<Item = Vec<u8>, Error = Error> {
-        body.concat2()
-            .from_err::<Error>()
-            .map(|chunk| chunk.to_vec())
+    // Read container upload, computing its HMAC and parsing the archive as
+    // the chunks arrive
+    fn fetch_upload(
+        body: Body,
+        secret: &[u8],
+        size_hint: Option<u64>,
+        max_size: u64,
+        content_type: Option<String>,
+        content_encoding: Option<String>,
+        limits: Limits,
+    ) -> impl Future<Item = (HmacSha256, Data), Error = Error> {
+        // Reject early when the client announces the size
+        let size_hint = size_hint.unwrap_or(0);
+        if size_hint > max_size {
+            return future::Either::A(future::err(Error::UploadTooLarge(max_size)));
+        }
+
+        let mac = match HmacSha256::new_varkey(secret) {
+            Ok(mac) => mac,
+            Err(err) => return future::Either::A(future::err(Error::from(err))),
+        };
+        let upload = Upload {
+            content_type,
+            content_encoding,
+            limits,
+            capacity: std::cmp::min(size_hint, MAX_UPLOAD_CAPACITY) as usize,
+            head: vec![],
+            parser: None,
+        };
+
+        // Dropping the accumulated upload on error (e.g. disconnect) discards
+        // the partial container
+        future::Either::B(
+            body.from_err::<Error>()
+                .fold(
+                    (mac, 0, upload),
+                    move |(mut mac, received, mut upload), chunk| {
+                        let received = received + chunk.len() as u64;
+                        if received > max_size {
+                            return Err(Error::UploadTooLarge(max_size));
+                        }
+                        mac.input(&chunk);
+                        upload.push(&chunk)?;
+                        Ok((mac, received, upload))
+                    },
+                )
+                .and_then(|(mac, _, upload)| upload.finish().map(|data| (mac, data))),
+        )
     }
 
     fn stringify_value<T: Serialize>(value: &T) -> Result<Body, Error> {
@@ -64,10 +163,7 @@ impl RPCService {
             .map_err(Error::from)
     }
 
-    fn compute_container(secret: &[u8], value: &[u8]) -> Result<String, Error> {
-        let mut mac = HmacSha256::new_varkey(secret)?;
-        mac.input(&value);
-
+    fn compute_container(mac: HmacSha256) -> String {
         let mut digest: [u8; 8] = [0; 8];
         digest.copy_from_slice(&mac.result().code()[..8]);
         let mut digest = u64::from_be_bytes(digest);
@@ -81,7 +177,32 @@ impl RPCService {
 
             result.push(CONTAINER_ALPHABET[m as usize]);
         }
-        Ok(result)
+        result
+    }
+
+    // `Content-Type` and `Content-Encoding` take priority over magic bytes
+    fn detect_archive(
+        content_type: Option<String>,
+        content_encoding: Option<String>,
+        value: &[u8],
+    ) -> Result<Archive, Error> {
+        let mime = content_type
+            .as_ref()
+            .and_then(|val| val.split(';').next())
+            .map(str::trim);
+        match mime {
+            Some("application/zip") | Some("application/x-zip-compressed") => {
+                return Ok(Archive::Zip);
+            }
+            _ => {}
+        }
+
+        match content_encoding {
+            Some(encoding) => Compression::from_content_encoding(&encoding)
+                .map(Archive::Tar)
+                .ok_or(Error::UnsupportedEncoding(encoding)),
+            None => Ok(Archive::detect(value)),
+        }
     }
 
     fn check_auth(&self, parts: &hyper::http::request::Parts) -> bool {
@@ -119,6 +240,7 @@ impl RPCService {
 struct Resource {
     status: StatusCode,
     mime: Option<String>,
+    headers: HeaderMap,
     sender: Option<String>,
     body: Body,
 }
@@ -158,6 +280,7 @@ impl hyper::service::Service for RPCService {
                         .map(|body| Resource {
                             status: StatusCode::OK,
                             mime: None,
+                            headers: HeaderMap::new(),
                             sender: None,
                             body,
                         }),
@@ -174,6 +297,49 @@ impl hyper::service::Service for RPCService {
                                 .map(|body| Resource {
                                     status: StatusCode::OK,
                                     mime: None,
+                                    headers: HeaderMap::new(),
+                                    sender: None,
+                                    body,
+                                }),
+                        )
+                    } else {
+                        Box::new(future::err(Error::NotAuthorized))
+                    }
+                }
+                (Method::POST, "/_drain") => {
+                    if is_authorized {
+                        let progress = self.node.lock().expect("lock to acquire").start_drain();
+                        Box::new(
+                            RPCService::stringify_value(&progress)
+                                .into_future()
+                                .map(|body| Resource {
+                                    status: StatusCode::OK,
+                                    mime: None,
+                                    headers: HeaderMap::new(),
+                                    sender: None,
+                                    body,
+                                }),
+                        )
+                    } else {
+                        Box::new(future::err(Error::NotAuthorized))
+                    }
+                }
+                (Method::POST, "/_ping-req") => {
+                    if is_authorized {
+                        let node = self.node.clone();
+                        Box::new(
+                            RPCService::fetch_json(body)
+                                .and_then(move |ping_req| {
+                                    node.lock()
+                                        .expect("lock to acquire")
+                                        .recv_ping_req(&ping_req)
+                                })
+                                .and_then(|reply| reply.ok_or(Error::PingFailed))
+                                .and_then(|res| RPCService::stringify_value(&res))
+                                .map(|body| Resource {
+                                    status: StatusCode::OK,
+                                    mime: None,
+                                    headers: HeaderMap::new(),
                                     sender: None,
                                     body,
                                 }),
@@ -186,10 +352,17 @@ impl hyper::service::Service for RPCService {
                     self.node
                         .lock()
                         .expect("lock to acquire")
-                        .fetch(&container, &resource[1..], redirect)
+                        .fetch(
+                            &container,
+                            &resource[1..],
+                            parts.uri.query(),
+                            redirect,
+                            &parts.headers,
+                        )
                         .map(|response| Resource {
-                            status: StatusCode::OK,
+                            status: response.status,
                             mime: Some(response.mime),
+                            headers: response.headers,
                             sender: Some(response.peer),
                             body: response.body,
                         }),
@@ -200,6 +373,7 @@ impl hyper::service::Service for RPCService {
                         .map(|body| Resource {
                             status: StatusCode::OK,
                             mime: None,
+                            headers: HeaderMap::new(),
                             sender: None,
                             body,
                         }),
@@ -208,25 +382,47 @@ impl hyper::service::Service for RPCService {
                     if is_authorized {
                         let node = self.node.clone();
                         let container_secret = self.config.container_secret.clone();
+                        let limits = Limits::from(&self.config);
+                        let content_type = parts
+                            .headers
+                            .get(hyper::header::CONTENT_TYPE)
+                            .map(|val| val.to_str().unwrap_or("").to_string());
+                        let content_encoding = parts
+                            .headers
+                            .get(hyper::header::CONTENT_ENCODING)
+                            .map(|val| val.to_str().unwrap_or("").to_string());
+                        let content_length = parts
+                            .headers
+                            .get(hyper::header::CONTENT_LENGTH)
+                            .and_then(|val| val.to_str().ok())
+                            .and_then(|val| val.parse::<u64>().ok());
 
                         Box::new(
-                            RPCService::fetch_raw(body)
-                                .and_then(move |value| {
-                                    RPCService::compute_container(&container_secret, &value)
-                                        .map(|container| (container, value))
-                                })
-                                .and_then(move |(container, value)| {
-                                    node.lock()
-                                        .expect("lock to acquire")
-                                        .store(&container, value, redirect)
-                                })
-                                .and_then(|res| RPCService::stringify_value(&res))
-                                .map(|body| Resource {
-                                    status: StatusCode::CREATED,
-                                    mime: None,
-                                    sender: None,
-                                    body,
-                                }),
+                            RPCService::fetch_upload(
+                                body,
+                                &container_secret,
+                                content_length,
+                                self.config.max_upload_size,
+                                content_type,
+                                content_encoding,
+                                limits,
+                            )
+                            .and_then(move |(mac, entry)| {
+                                let container = RPCService::compute_container(mac);
+                                node.lock().expect("lock to acquire").store(
+                                    &container,
+                                    Arc::new(entry),
+                                    redirect,
+                                )
+                            })
+                            .and_then(|res| RPCService::stringify_value(&res))
+                            .map(|body| Resource {
+                                status: StatusCode::CREATED,
+                                mime: None,
+                                headers: HeaderMap::new(),
+                                sender: None,
+                                body,
+                            }),
                         )
                     } else {
                         Box::new(future::err(Error::NotAuthorized))
@@ -241,8 +437,16 @@ impl hyper::service::Service for RPCService {
                     let status = match err {
                         Error::NotFound => StatusCode::NOT_FOUND,
                         Error::BadRequest => StatusCode::BAD_REQUEST,
+                        Error::Manifest(_) => StatusCode::BAD_REQUEST,
+                        Error::UnsafePath(_) => StatusCode::BAD_REQUEST,
+                        Error::ArchiveTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                        Error::UploadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                        Error::UnsupportedEncoding(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
                         Error::NonLocalStore(_) => StatusCode::GONE,
+                        Error::OverBudget(_) => StatusCode::INSUFFICIENT_STORAGE,
                         Error::NotAuthorized => StatusCode::UNAUTHORIZED,
+                        Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                        Error::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
                         _ => StatusCode::INTERNAL_SERVER_ERROR,
                     };
                     let json = serde_json::to_string(&response::Error { error: err })
@@ -250,6 +454,7 @@ impl hyper::service::Service for RPCService {
                     Ok(Resource {
                         status,
                         mime: None,
+                        headers: HeaderMap::new(),
                         sender: None,
                         body: Body::from(json),
                     })
@@ -260,7 +465,14 @@ impl hyper::service::Service for RPCService {
                     let mime = resource
                         .mime
                         .unwrap_or_else(|| "application/json".to_string());
-                    res.header(hyper::header::CONTENT_TYPE, mime);
+
+                    // Content type may be overridden by `_headers`
+                    if !resource.headers.contains_key(hyper::header::CONTENT_TYPE) {
+                        res.header(hyper::header::CONTENT_TYPE, mime);
+                    }
+                    for (name, value) in resource.headers.iter() {
+                        res.header(name, value.clone());
+                    }
                     if let Some(sender) = resource.sender {
                         res.header("x-naught-sender", sender);
                     }
@@ -284,18 +496,68 @@ impl IntoFuture for RPCService {
 mod tests {
     use super::*;
 
+    use std::io;
+
+    use crate::data::tar_blob;
+
+    fn compute_container(secret: &[u8], value: &[u8]) -> Result<String, Error> {
+        let mut mac = HmacSha256::new_varkey(secret)?;
+        mac.input(value);
+        Ok(RPCService::compute_container(mac))
+    }
+
     #[test]
     fn it_should_compute_container_key() {
-        let container =
-            RPCService::compute_container(&[0; 8], &[1; 16]).expect("compute to not fail");
+        let container = compute_container(&[0; 8], &[1; 16]).expect("compute to not fail");
         assert_eq!(container, "5s5hm016gajd2");
 
-        let container =
-            RPCService::compute_container(&[1; 8], &[1; 16]).expect("compute to not fail");
+        let container = compute_container(&[1; 8], &[1; 16]).expect("compute to not fail");
         assert_eq!(container, "yavn6x3gtu563");
 
-        let container =
-            RPCService::compute_container(&[1; 8], &[2; 16]).expect("compute to not fail");
+        let container = compute_container(&[1; 8], &[2; 16]).expect("compute to not fail");
         assert_eq!(container, "9zzezdv0218f3");
     }
+
+    fn upload(chunks: Vec<Result<Vec<u8>, io::Error>>, max_size: u64) -> Result<Data, Error> {
+        let body = Body::wrap_stream(futures::stream::iter_result(chunks));
+        let limits = Limits::from(&Config::new(vec![0], (0, 0)));
+        RPCService::fetch_upload(body, &[0; 8], None, max_size, None, None, limits)
+            .wait()
+            .map(|(_, data)| data)
+    }
+
+    #[test]
+    fn it_should_stream_uploads() {
+        let tar = tar_blob(&[("index.html", b"hello")]);
+        let chunks = tar.chunks(100).map(|chunk| Ok(chunk.to_vec())).collect();
+        let data = upload(chunks, 1024 * 1024).expect("upload to succeed");
+        let (_, body) = data.serve("").unwrap().encode("").unwrap();
+        assert_eq!(&body[..], b"hello");
+
+        match upload(vec![Ok(tar)], 1024) {
+            Err(Error::UploadTooLarge(1024)) => {}
+            _ => panic!("expected upload to be rejected"),
+        }
+
+        let body = Body::empty();
+        let limits = Limits::from(&Config::new(vec![0], (0, 0)));
+        match RPCService::fetch_upload(body, &[0; 8], Some(17), 16, None, None, limits).wait() {
+            Err(Error::UploadTooLarge(16)) => {}
+            _ => panic!("expected upload to be rejected"),
+        }
+
+        // Unsafe entry is rejected before the rest of the upload arrives
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..9].copy_from_slice(b"../passwd");
+        header.set_size(0);
+        header.set_cksum();
+        let chunks = vec![
+            Ok(header.as_bytes().to_vec()),
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "disconnect")),
+        ];
+        match upload(chunks, 1024 * 1024) {
+            Err(Error::UnsafePath(_)) => {}
+            _ => panic!("expected unsafe path error"),
+        }
+    }
 }
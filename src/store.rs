@@ -0,0 +1,527 @@
+extern crate futures;
+extern crate serde;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use futures::future;
+use futures::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::data::{Archive, Data, Limits};
+use crate::error::Error;
+
+const BLOB_EXT: &str = "tar";
+const INDEX_EXT: &str = "json";
+const TMP_EXT: &str = "tmp";
+
+pub type ContainerIter<'a> = Box<Iterator<Item = (String, Arc<Data>)> + 'a>;
+pub type FutureData = Box<Future<Item = Arc<Data>, Error = Error> + Send>;
+
+// Storage for containers held by the `Node`. Implement this to embed naught
+// with a custom backend.
+pub trait ContainerStore: Send {
+    fn put(&mut self, container: &str, data: Arc<Data>) -> Result<(), Error>;
+    fn get(&self, container: &str) -> Option<Arc<Data>>;
+    fn contains(&self, container: &str) -> bool;
+    fn remove(&mut self, container: &str) -> Result<(), Error>;
+    fn list(&self) -> Vec<String>;
+
+    // Same as `get`, but slow loading should happen when the future is
+    // polled. It is called with the node locked, the future runs unlocked.
+    fn load(&self, container: &str) -> FutureData {
+        Box::new(future::result(self.get(container).ok_or(Error::NotFound)))
+    }
+
+    // Size of stored container, see `Data::size`
+    fn size(&self, container: &str) -> Option<u64> {
+        self.get(container).map(|data| data.size())
+    }
+
+    // Total size of stored containers, see `Data::size`
+    fn used_bytes(&self) -> u64 {
+        self.iter().map(|(_, data)| data.size()).sum()
+    }
+
+    fn iter(&self) -> ContainerIter<'_> {
+        Box::new(
+            self.list()
+                .into_iter()
+                .filter_map(move |container| self.get(&container).map(|data| (container, data))),
+        )
+    }
+}
+
+pub fn from_config(config: &Config) -> Result<Box<ContainerStore>, Error> {
+    match config.data_dir {
+        Some(ref dir) => Ok(Box::new(DiskStore::open(
+            dir,
+            Limits::from(config),
+            config.cache_size,
+        )?)),
+        None => Ok(Box::new(MemoryStore::new())),
+    }
+}
+
+// Default store, everything is lost on restart
+
+#[derive(Default)]
+pub struct MemoryStore {
+    map: HashMap<String, Arc<Data>>,
+    used: u64,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl ContainerStore for MemoryStore {
+    fn put(&mut self, container: &str, data: Arc<Data>) -> Result<(), Error> {
+        self.used += data.size();
+        if let Some(old) = self.map.insert(container.to_string(), data) {
+            self.used -= old.size();
+        }
+        Ok(())
+    }
+
+    fn get(&self, container: &str) -> Option<Arc<Data>> {
+        self.map.get(container).cloned()
+    }
+
+    fn contains(&self, container: &str) -> bool {
+        self.map.contains_key(container)
+    }
+
+    fn remove(&mut self, container: &str) -> Result<(), Error> {
+        if let Some(old) = self.map.remove(container) {
+            self.used -= old.size();
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.map.keys().cloned().collect()
+    }
+
+    fn size(&self, container: &str) -> Option<u64> {
+        self.map.get(container).map(|data| data.size())
+    }
+
+    fn used_bytes(&self) -> u64 {
+        self.used
+    }
+
+    fn iter(&self) -> ContainerIter<'_> {
+        Box::new(
+            self.map
+                .iter()
+                .map(|(container, data)| (container.clone(), data.clone())),
+        )
+    }
+}
+
+// Directory-backed store. Original blobs are kept as `<container>.tar` next
+// to `<container>.json` with their sizes. Only the small files are read on
+// startup, blobs are parsed on first access.
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    size: u64,
+}
+
+// Least recently used parsed containers, up to `capacity` bytes
+struct Cache {
+    map: HashMap<String, (Arc<Data>, u64)>,
+    used: u64,
+    capacity: u64,
+    tick: u64,
+}
+
+impl Cache {
+    fn new(capacity: u64) -> Self {
+        Cache {
+            map: HashMap::new(),
+            used: 0,
+            capacity,
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, container: &str) -> Option<Arc<Data>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.map.get_mut(container).map(|(data, used_at)| {
+            *used_at = tick;
+            data.clone()
+        })
+    }
+
+    fn insert(&mut self, container: &str, data: Arc<Data>) {
+        self.remove(container);
+
+        // Would evict everything else
+        let size = data.size();
+        if size > self.capacity {
+            return;
+        }
+
+        while self.used + size > self.capacity {
+            let oldest = self
+                .map
+                .iter()
+                .min_by_key(|(_, (_, used_at))| *used_at)
+                .map(|(container, _)| container.clone());
+            match oldest {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.used += size;
+        self.map.insert(container.to_string(), (data, self.tick));
+    }
+
+    fn remove(&mut self, container: &str) {
+        if let Some((data, _)) = self.map.remove(container) {
+            self.used -= data.size();
+        }
+    }
+}
+
+enum DiskWrite {
+    Put(String, Arc<Data>),
+    Remove(String),
+}
+
+pub struct DiskStore {
+    root: PathBuf,
+    limits: Limits,
+    index: HashMap<String, u64>,
+    cache: Arc<Mutex<Cache>>,
+    // Put, but not yet written to disk
+    pending: Arc<Mutex<HashMap<String, Arc<Data>>>>,
+    // Disk is written on a separate thread, not with the node locked
+    writes: Option<mpsc::Sender<DiskWrite>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl DiskStore {
+    pub fn open<P: AsRef<Path>>(root: P, limits: Limits, cache_size: u64) -> Result<Self, Error> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+
+        let mut index = HashMap::new();
+        for entry in fs::read_dir(&root)? {
+            let path = entry?.path();
+            let ext = path.extension().and_then(|ext| ext.to_str());
+            let container = path.file_stem().and_then(|stem| stem.to_str());
+            let (ext, container) = match (ext, container) {
+                (Some(ext), Some(container)) => (ext, container.to_string()),
+                _ => continue,
+            };
+
+            // Interrupted writes and removals
+            if ext == TMP_EXT || (ext == BLOB_EXT && !path.with_extension(INDEX_EXT).exists()) {
+                warn!("disk store: removing incomplete file: {:?}", path);
+                fs::remove_file(&path)?;
+                continue;
+            }
+            if ext != INDEX_EXT {
+                continue;
+            }
+
+            let IndexEntry { size } = serde_json::from_slice(&fs::read(&path)?)?;
+            if path.with_extension(BLOB_EXT).exists() {
+                trace!(
+                    "disk store: indexed container: {} size: {}",
+                    container,
+                    size
+                );
+                index.insert(container, size);
+            } else {
+                warn!("disk store: missing blob for container: {}", container);
+            }
+        }
+
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let (writes, queue) = mpsc::channel();
+        let writer = {
+            let root = root.clone();
+            let pending = pending.clone();
+            thread::spawn(move || DiskStore::write_queue(&root, queue, &pending))
+        };
+
+        Ok(DiskStore {
+            root,
+            limits,
+            index,
+            cache: Arc::new(Mutex::new(Cache::new(cache_size))),
+            pending,
+            writes: Some(writes),
+            writer: Some(writer),
+        })
+    }
+
+    fn blob_path(root: &Path, container: &str) -> PathBuf {
+        root.join(container).with_extension(BLOB_EXT)
+    }
+
+    fn write_queue(
+        root: &Path,
+        queue: mpsc::Receiver<DiskWrite>,
+        pending: &Mutex<HashMap<String, Arc<Data>>>,
+    ) {
+        for write in queue {
+            match write {
+                DiskWrite::Put(container, data) => {
+                    if let Err(err) = DiskStore::write_container(root, &container, &data) {
+                        error!(
+                            "disk store: failed to write {} due to: {:?}",
+                            container, err
+                        );
+                    }
+
+                    // Unless put again since
+                    let mut pending = pending.lock().expect("lock to acquire");
+                    let last = pending.get(&container).map(|last| Arc::ptr_eq(last, &data));
+                    if last == Some(true) {
+                        pending.remove(&container);
+                    }
+                }
+                DiskWrite::Remove(container) => {
+                    if let Err(err) = DiskStore::remove_container(root, &container) {
+                        error!(
+                            "disk store: failed to remove {} due to: {:?}",
+                            container, err
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Blob is written first, container is not indexed until it is complete
+    fn write_container(root: &Path, container: &str, data: &Data) -> Result<(), Error> {
+        let path = DiskStore::blob_path(root, container);
+        DiskStore::write_atomic(&path, data.blob())?;
+
+        let index = serde_json::to_vec(&IndexEntry { size: data.size() })?;
+        DiskStore::write_atomic(&path.with_extension(INDEX_EXT), &index)?;
+
+        // Renames are durable once the directory is synced
+        fs::File::open(root)?.sync_all()?;
+        Ok(())
+    }
+
+    fn remove_container(root: &Path, container: &str) -> Result<(), Error> {
+        let path = DiskStore::blob_path(root, container);
+        fs::remove_file(path.with_extension(INDEX_EXT))?;
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), Error> {
+        let tmp = path.with_extension(TMP_EXT);
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn read(container: &str, path: &Path, limits: Limits) -> Result<Data, Error> {
+        trace!("disk store: loading container: {}", container);
+        let blob = fs::read(path)?;
+        let archive = Archive::detect(&blob);
+        Data::from_archive(blob, archive, limits)
+    }
+
+    fn cached(&self, container: &str) -> Option<Arc<Data>> {
+        if let Some(data) = self.pending.lock().expect("lock to acquire").get(container) {
+            return Some(data.clone());
+        }
+        self.cache.lock().expect("lock to acquire").get(container)
+    }
+
+    fn write(&self, write: DiskWrite) {
+        if let Some(ref writes) = self.writes {
+            writes.send(write).expect("disk writer to be running");
+        }
+    }
+}
+
+// Pending writes are completed before the store is gone
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        self.writes.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl ContainerStore for DiskStore {
+    fn put(&mut self, container: &str, data: Arc<Data>) -> Result<(), Error> {
+        self.index.insert(container.to_string(), data.size());
+        self.pending
+            .lock()
+            .expect("lock to acquire")
+            .insert(container.to_string(), data.clone());
+        self.cache
+            .lock()
+            .expect("lock to acquire")
+            .insert(container, data.clone());
+        self.write(DiskWrite::Put(container.to_string(), data));
+        Ok(())
+    }
+
+    fn get(&self, container: &str) -> Option<Arc<Data>> {
+        if !self.index.contains_key(container) {
+            return None;
+        }
+
+        if let Some(data) = self.cached(container) {
+            return Some(data);
+        }
+
+        let path = DiskStore::blob_path(&self.root, container);
+        match DiskStore::read(container, &path, self.limits) {
+            Ok(data) => {
+                let data = Arc::new(data);
+                self.cache
+                    .lock()
+                    .expect("lock to acquire")
+                    .insert(container, data.clone());
+                Some(data)
+            }
+            Err(err) => {
+                error!("disk store: failed to load {} due to: {:?}", container, err);
+                None
+            }
+        }
+    }
+
+    // Parsing and compressing the blob happens in the returned future
+    fn load(&self, container: &str) -> FutureData {
+        if !self.index.contains_key(container) {
+            return Box::new(future::err(Error::NotFound));
+        }
+
+        if let Some(data) = self.cached(container) {
+            return Box::new(future::ok(data));
+        }
+
+        let container = container.to_string();
+        let path = DiskStore::blob_path(&self.root, &container);
+        let cache = self.cache.clone();
+        let limits = self.limits;
+        Box::new(future::lazy(move || {
+            let data = Arc::new(DiskStore::read(&container, &path, limits)?);
+            cache
+                .lock()
+                .expect("lock to acquire")
+                .insert(&container, data.clone());
+            Ok(data)
+        }))
+    }
+
+    fn contains(&self, container: &str) -> bool {
+        self.index.contains_key(container)
+    }
+
+    fn remove(&mut self, container: &str) -> Result<(), Error> {
+        if self.index.remove(container).is_none() {
+            return Ok(());
+        }
+
+        self.pending
+            .lock()
+            .expect("lock to acquire")
+            .remove(container);
+        self.cache
+            .lock()
+            .expect("lock to acquire")
+            .remove(container);
+        self.write(DiskWrite::Remove(container.to_string()));
+        Ok(())
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.index.keys().cloned().collect()
+    }
+
+    // Does not load the blob
+    fn size(&self, container: &str) -> Option<u64> {
+        self.index.get(container).cloned()
+    }
+
+    fn used_bytes(&self) -> u64 {
+        self.index.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::data::tar_data;
+
+    #[test]
+    fn it_should_restore_containers_from_disk() {
+        let root = std::env::temp_dir().join(format!("naught-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let limits = Limits::from(&Config::new(vec![0], (0, 0)));
+
+        {
+            let mut store = DiskStore::open(&root, limits, 1024 * 1024).expect("store to open");
+            let data = tar_data(&[("index.html", b"hello")]);
+            store.put("abc", Arc::new(data)).expect("put to succeed");
+            store
+                .put("def", Arc::new(tar_data(&[("index.html", b"hello")])))
+                .expect("put to succeed");
+            store.remove("def").expect("remove to succeed");
+        }
+
+        let store = DiskStore::open(&root, limits, 1024 * 1024).expect("store to reopen");
+        assert_eq!(store.list(), vec!["abc".to_string()]);
+        assert!(!store.contains("def"));
+        assert_eq!(store.used_bytes(), store.get("abc").unwrap().size());
+
+        let data = store.get("abc").expect("container to load");
+        assert_eq!(
+            &data
+                .serve("")
+                .expect("index to be present")
+                .encode("")
+                .unwrap()
+                .1[..],
+            b"hello"
+        );
+
+        fs::remove_dir_all(&root).expect("cleanup to succeed");
+    }
+
+    #[test]
+    fn it_should_evict_least_recently_used() {
+        let data = |name: &str| Arc::new(tar_data(&[(name, b"hello")]));
+        let mut cache = Cache::new(data("a").size() * 2);
+
+        cache.insert("a", data("a"));
+        cache.insert("b", data("b"));
+        assert!(cache.get("a").is_some());
+
+        cache.insert("c", data("c"));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+}